@@ -10,8 +10,9 @@ if [ $? -eq 0 ]; then
     qemu-system-riscv64 \
         -machine virt \
         -cpu rv64 \
-        -smp 1 \
+        -smp 4 \
         -m 128M \
+        -bios none \
         -nographic \
         -monitor none \
         -d int,cpu_reset \
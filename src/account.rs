@@ -0,0 +1,174 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use crate::task::ResourceRequirements;
+
+/// Account charged for tasks that don't name one
+pub const DEFAULT_ACCOUNT: &str = "default";
+
+/// What happens to a submission that would exceed an account's queued-job limit
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum QuotaAction {
+    #[default]
+    Reject,
+    /// Queue the job held, so it waits until an operator releases it
+    Hold,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct AccountQuota {
+    /// Most CPUs the account's running jobs may hold at once
+    pub max_cpus: Option<u32>,
+    /// Most memory (KB) the account's running jobs may hold at once
+    pub max_memory: Option<u64>,
+    /// Most jobs the account may have queued or waiting on dependencies
+    pub max_queued_jobs: Option<usize>,
+    pub over_queue_limit: QuotaAction,
+}
+
+impl AccountQuota {
+    pub fn permits(&self, cpu: u32, memory: u64) -> bool {
+        self.max_cpus.is_none_or(|max| cpu <= max) && self.max_memory.is_none_or(|max| memory <= max)
+    }
+}
+
+/// Controls how an account's recent usage lowers the priority of its jobs
+#[derive(Debug, Clone)]
+pub struct FairSharePolicy {
+    /// Ticks after which recorded usage counts half as much (0 disables decay)
+    pub half_life: u64,
+    /// Priority bonus given to an account with no recent usage
+    pub weight: u32,
+}
+
+impl FairSharePolicy {
+    pub const fn disabled() -> Self {
+        FairSharePolicy {
+            half_life: 0,
+            weight: 0,
+        }
+    }
+}
+
+/// Fair share is off unless enabled with `BatchSystem::set_fair_share_policy`
+impl Default for FairSharePolicy {
+    fn default() -> Self {
+        FairSharePolicy::disabled()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Account {
+    pub quota: AccountQuota,
+    /// CPUs held by the account's running jobs
+    pub cpus_in_use: u32,
+    /// Memory (KB) held by the account's running jobs
+    pub memory_in_use: u64,
+    /// CPU-ticks consumed by the account's jobs, halved every half-life
+    usage: u64,
+    last_decay: u64,
+}
+
+impl Account {
+    pub fn new(quota: AccountQuota, now: u64) -> Self {
+        Account {
+            quota,
+            cpus_in_use: 0,
+            memory_in_use: 0,
+            usage: 0,
+            last_decay: now,
+        }
+    }
+
+    /// Whether the account may take `requirements` on top of what it already holds
+    pub fn can_allocate(&self, requirements: &ResourceRequirements) -> bool {
+        self.quota.permits(
+            self.cpus_in_use + requirements.cpu,
+            self.memory_in_use + requirements.memory,
+        )
+    }
+
+    pub fn allocate(&mut self, requirements: &ResourceRequirements) {
+        self.cpus_in_use += requirements.cpu;
+        self.memory_in_use += requirements.memory;
+    }
+
+    /// Whether the account's running jobs hold at least `requirements`
+    pub fn holds(&self, requirements: &ResourceRequirements) -> bool {
+        requirements.cpu <= self.cpus_in_use && requirements.memory <= self.memory_in_use
+    }
+
+    /// Returns `requirements` to the account. Returns false, changing
+    /// nothing, unless the account holds at least that much.
+    pub fn release(&mut self, requirements: &ResourceRequirements) -> bool {
+        let cpus = self.cpus_in_use.checked_sub(requirements.cpu);
+        let memory = self.memory_in_use.checked_sub(requirements.memory);
+        match (cpus, memory) {
+            (Some(cpus), Some(memory)) => {
+                self.cpus_in_use = cpus;
+                self.memory_in_use = memory;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    pub fn record_usage(&mut self, cpu_ticks: u64, now: u64, half_life: u64) {
+        self.decay(now, half_life);
+        self.usage = self.usage.saturating_add(cpu_ticks);
+    }
+
+    pub fn usage(&mut self, now: u64, half_life: u64) -> u64 {
+        self.decay(now, half_life);
+        self.usage
+    }
+
+    fn decay(&mut self, now: u64, half_life: u64) {
+        if half_life == 0 {
+            return;
+        }
+        let half_lives = now.saturating_sub(self.last_decay) / half_life;
+        if half_lives > 0 {
+            self.usage = self.usage.checked_shr(half_lives as u32).unwrap_or(0);
+            self.last_decay += half_lives * half_life;
+        }
+    }
+}
+
+/// Priority bonus per account at one point in time. An account gets the
+/// policy's full weight scaled by the share of recent usage that was *not*
+/// its own, so heavy users drop behind accounts that have run little.
+#[derive(Debug, Clone, Default)]
+pub struct FairShareFactors {
+    bonuses: BTreeMap<String, u32>,
+    /// Bonus for accounts with no recorded usage
+    idle_bonus: u32,
+}
+
+impl FairShareFactors {
+    pub fn compute(accounts: &mut BTreeMap<String, Account>, policy: &FairSharePolicy, now: u64) -> Self {
+        let usage: BTreeMap<String, u64> = accounts
+            .iter_mut()
+            .map(|(name, account)| (name.clone(), account.usage(now, policy.half_life)))
+            .collect();
+        let total: u64 = usage.values().sum();
+
+        let bonuses = usage
+            .into_iter()
+            .map(|(name, used)| {
+                let bonus = match total {
+                    0 => policy.weight,
+                    _ => (policy.weight as u64 * (total - used) / total) as u32,
+                };
+                (name, bonus)
+            })
+            .collect();
+        FairShareFactors {
+            bonuses,
+            idle_bonus: policy.weight,
+        }
+    }
+
+    pub fn bonus(&self, account: &str) -> u32 {
+        self.bonuses.get(account).copied().unwrap_or(self.idle_bonus)
+    }
+}
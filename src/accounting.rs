@@ -0,0 +1,271 @@
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::job_table::{JobRecord, JobTable};
+use crate::task::{JobId, ResourceRequirements, TaskStatus};
+use crate::timer::TICKS_PER_SECOND;
+use crate::println;
+
+/// Number of equal slices the run is split into when reporting utilisation over time
+pub const UTILISATION_SLICES: u64 = 10;
+
+/// Usage and outcome of one job, as reported at the end of a run
+#[derive(Debug, Clone)]
+pub struct JobAccounting {
+    pub id: JobId,
+    pub executable: String,
+    pub account: String,
+    pub status: TaskStatus,
+    /// Ticks from submission to first start (None if the job never started)
+    pub queue_wait: Option<u64>,
+    /// Ticks spent running, summed over attempts
+    pub runtime: u64,
+    pub cpu_ticks: u64,
+    /// Peak memory in KB
+    pub peak_memory: u64,
+    pub attempts: usize,
+    pub exit_code: Option<i32>,
+    pub exit_reason: String,
+}
+
+impl JobAccounting {
+    pub fn new(job: &JobRecord) -> Self {
+        let exit_reason = match (&job.failure_reason, &job.status) {
+            (Some(reason), _) => reason.clone(),
+            (None, TaskStatus::Completed) => String::from("completed"),
+            (None, status) => format!("{:?}", status),
+        };
+        JobAccounting {
+            id: job.id,
+            executable: job.task.executable.clone(),
+            account: job.task.account.clone(),
+            status: job.status.clone(),
+            queue_wait: job.attempts.first().map(|a| a.start_tick.saturating_sub(job.submit_tick)),
+            runtime: job.attempts.iter().map(|a| a.end_tick.saturating_sub(a.start_tick)).sum(),
+            cpu_ticks: job.cpu_ticks,
+            peak_memory: job.peak_memory,
+            attempts: job.attempts.len(),
+            exit_code: job.exit_code,
+            exit_reason,
+        }
+    }
+
+    pub const CSV_HEADER: &'static str =
+        "job,executable,account,status,queue_wait,runtime,cpu_ticks,peak_memory_kb,attempts,exit_code,exit_reason";
+
+    pub fn to_csv(&self) -> String {
+        format!("{},{},{},{:?},{},{},{},{},{},{},{}",
+            self.id,
+            csv_field(&self.executable),
+            csv_field(&self.account),
+            self.status,
+            self.queue_wait.map(|w| format!("{}", w)).unwrap_or_default(),
+            self.runtime,
+            self.cpu_ticks,
+            self.peak_memory,
+            self.attempts,
+            self.exit_code.map(|c| format!("{}", c)).unwrap_or_default(),
+            csv_field(&self.exit_reason))
+    }
+
+    pub fn to_json(&self) -> String {
+        format!("{{\"type\":\"job\",\"job\":{},\"executable\":{},\"account\":{},\"status\":\"{:?}\",\"queue_wait\":{},\"runtime\":{},\"cpu_ticks\":{},\"peak_memory_kb\":{},\"attempts\":{},\"exit_code\":{},\"exit_reason\":{}}}",
+            self.id,
+            json_string(&self.executable),
+            json_string(&self.account),
+            self.status,
+            json_option(self.queue_wait),
+            self.runtime,
+            self.cpu_ticks,
+            self.peak_memory,
+            self.attempts,
+            json_option(self.exit_code),
+            json_string(&self.exit_reason))
+    }
+}
+
+/// How much of one resource was in use, as a percentage of its capacity
+#[derive(Debug, Clone)]
+pub struct Utilisation {
+    pub resource: String,
+    pub capacity: u64,
+    /// Percentage over the whole run
+    pub average: u64,
+    /// Percentage in each of `UTILISATION_SLICES` equal slices of the run
+    pub over_time: Vec<u64>,
+}
+
+/// Statistics for a whole batch run, built from the job table once every job has finished
+#[derive(Debug, Clone)]
+pub struct RunReport {
+    pub jobs: Vec<JobAccounting>,
+    pub start_tick: u64,
+    pub end_tick: u64,
+    pub completed: usize,
+    /// Averages over the jobs that ran
+    pub average_queue_wait: u64,
+    pub average_runtime: u64,
+    pub average_cpu_ticks: u64,
+    pub utilisation: Vec<Utilisation>,
+}
+
+impl RunReport {
+    pub fn new(table: &JobTable, total_resources: &ResourceRequirements) -> Self {
+        let start_tick = table.iter().map(|job| job.submit_tick).min().unwrap_or(0);
+        let end_tick = table.iter().filter_map(|job| job.end_tick).max().unwrap_or(start_tick);
+        let jobs: Vec<JobAccounting> = table.iter().map(JobAccounting::new).collect();
+
+        let ran: Vec<&JobAccounting> = jobs.iter().filter(|job| job.attempts > 0).collect();
+        let average = |value: fn(&JobAccounting) -> u64| match ran.len() {
+            0 => 0,
+            n => ran.iter().map(|job| value(job)).sum::<u64>() / n as u64,
+        };
+
+        let mut resources: Vec<(String, u64)> = Vec::new();
+        resources.push((String::from("cpu"), total_resources.cpu as u64));
+        resources.push((String::from("memory"), total_resources.memory));
+        for (name, &amount) in &total_resources.generic {
+            resources.push((name.clone(), amount));
+        }
+        let utilisation = resources
+            .into_iter()
+            .map(|(resource, capacity)| {
+                let slice = (end_tick - start_tick).div_ceil(UTILISATION_SLICES).max(1);
+                let over_time = (0..UTILISATION_SLICES)
+                    .map(|n| start_tick + n * slice)
+                    .map(|from| utilisation(table, &resource, capacity, from, from + slice))
+                    .collect();
+                Utilisation {
+                    average: utilisation(table, &resource, capacity, start_tick, end_tick),
+                    resource,
+                    capacity,
+                    over_time,
+                }
+            })
+            .collect();
+
+        RunReport {
+            start_tick,
+            end_tick,
+            completed: jobs.iter().filter(|job| matches!(job.status, TaskStatus::Completed)).count(),
+            average_queue_wait: average(|job| job.queue_wait.unwrap_or(0)),
+            average_runtime: average(|job| job.runtime),
+            average_cpu_ticks: average(|job| job.cpu_ticks),
+            utilisation,
+            jobs,
+        }
+    }
+
+    /// Ticks from the first submission to the last job finishing
+    pub fn makespan(&self) -> u64 {
+        self.end_tick - self.start_tick
+    }
+
+    /// Completed jobs per minute, in hundredths
+    pub fn throughput(&self) -> u64 {
+        match self.makespan() {
+            0 => 0,
+            makespan => self.completed as u64 * 60 * TICKS_PER_SECOND * 100 / makespan,
+        }
+    }
+
+    /// Prints the report for people, then as `[ACCT-CSV]` and `[ACCT-JSON]`
+    /// lines for scripts
+    pub fn print(&self) {
+        let throughput = self.throughput();
+        println!("[ACCT] Run report:");
+        println!("  - Jobs: {} ({} completed)", self.jobs.len(), self.completed);
+        println!("  - Makespan: {} ticks (ticks {}-{})", self.makespan(), self.start_tick, self.end_tick);
+        println!("  - Throughput: {}.{:02} jobs/min", throughput / 100, throughput % 100);
+        println!("  - Average queue wait: {} ticks", self.average_queue_wait);
+        println!("  - Average runtime: {} ticks", self.average_runtime);
+        println!("  - Average CPU time: {} ticks", self.average_cpu_ticks);
+        for utilisation in &self.utilisation {
+            println!("  - {} utilisation: {}% of {} (over time: {:?})",
+                utilisation.resource,
+                utilisation.average,
+                utilisation.capacity,
+                utilisation.over_time);
+        }
+
+        println!("[ACCT-CSV] {}", JobAccounting::CSV_HEADER);
+        for job in &self.jobs {
+            println!("[ACCT-CSV] {}", job.to_csv());
+        }
+        for job in &self.jobs {
+            println!("[ACCT-JSON] {}", job.to_json());
+        }
+        println!("[ACCT-JSON] {}", self.summary_json());
+    }
+
+    fn summary_json(&self) -> String {
+        let utilisation: Vec<String> = self
+            .utilisation
+            .iter()
+            .map(|u| format!("{}:{{\"capacity\":{},\"average\":{},\"over_time\":{:?}}}",
+                json_string(&u.resource), u.capacity, u.average, u.over_time))
+            .collect();
+        format!("{{\"type\":\"summary\",\"jobs\":{},\"completed\":{},\"start_tick\":{},\"end_tick\":{},\"makespan\":{},\"throughput_per_min_x100\":{},\"average_queue_wait\":{},\"average_runtime\":{},\"average_cpu_ticks\":{},\"utilisation\":{{{}}}}}",
+            self.jobs.len(),
+            self.completed,
+            self.start_tick,
+            self.end_tick,
+            self.makespan(),
+            self.throughput(),
+            self.average_queue_wait,
+            self.average_runtime,
+            self.average_cpu_ticks,
+            utilisation.join(","))
+    }
+}
+
+/// Percentage of `capacity` of a resource held by running jobs between `from` and `to`
+fn utilisation(table: &JobTable, resource: &str, capacity: u64, from: u64, to: u64) -> u64 {
+    if capacity == 0 || to <= from {
+        return 0;
+    }
+    let mut used = 0;
+    for job in table.iter() {
+        let requirements = &job.task.resource_requirements;
+        let amount = match resource {
+            "cpu" => requirements.cpu as u64,
+            "memory" => requirements.memory,
+            name => requirements.get_generic(name),
+        };
+        for attempt in &job.attempts {
+            let overlap = attempt.end_tick.min(to).saturating_sub(attempt.start_tick.max(from));
+            used += amount * overlap;
+        }
+    }
+    used * 100 / (capacity * (to - from))
+}
+
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        String::from(value)
+    }
+}
+
+fn json_string(value: &str) -> String {
+    let mut escaped = String::from("\"");
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped.push('"');
+    escaped
+}
+
+fn json_option<T: core::fmt::Display>(value: Option<T>) -> String {
+    match value {
+        Some(value) => format!("{}", value),
+        None => String::from("null"),
+    }
+}
@@ -1,123 +1,1256 @@
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::String;
 use alloc::sync::Arc;
-use spin::Mutex;
-use crate::task::{Task, ResourceRequirements, TaskStatus};
-use crate::resource_manager::ResourceManager;
-use crate::scheduler::Scheduler;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use crate::accounting::RunReport;
+use crate::account::{AccountQuota, FairSharePolicy, QuotaAction};
+use crate::task::{JobId, Tid, RetryOn, Task, TaskLimits, ResourceRequirements, TaskStatus};
+use crate::executor::{ExitReason, JobContext, JobOutput, SpawnedThread, FAULT_EXIT_CODE};
+use crate::job_array::{substitute_index, ArrayId, ArrayStatus, ArrayTask, JobArray, MAX_ARRAY_SIZE};
+use crate::job_table::{JobRecord, JobTable};
+use crate::kernel_stack::KernelStack;
+use crate::resource_manager::{AllocationPolicy, ResourceManager};
+use crate::scheduler::{AgingPolicy, Reservation, RunningTask, Scheduler};
+use crate::load_balancer;
+use crate::percpu;
+use crate::irq_lock::{IrqSafeMutex, SpinMutex};
+use crate::programs;
+use crate::smp;
+use crate::futex::{self, FUTEXES};
+use crate::sync::SYNC;
+use crate::reservation::AdvanceReservation;
+use crate::timer;
+use crate::watchdog::{self, LimitKind, Overrun};
 use crate::println;
 
 pub struct BatchSystem {
-    scheduler: Mutex<Scheduler>,
-    resource_manager: Arc<Mutex<ResourceManager>>,
+    scheduler: IrqSafeMutex<Scheduler>,
+    resource_manager: Arc<SpinMutex<ResourceManager>>,
+    /// Every running job, including those in the middle of a slice on some hart
+    running: SpinMutex<BTreeMap<JobId, RunningTask>>,
+    /// Threads blocked in a syscall, off every run queue until woken
+    parked: SpinMutex<ParkedThreads>,
+    /// Bumped whenever a running job ends, so the run loop notices jobs reaped on other harts
+    jobs_reaped: AtomicU64,
+    next_job_id: AtomicU64,
+    jobs: SpinMutex<JobTable>,
+    blocked: SpinMutex<Vec<(JobId, Task)>>,
+    cancel_requests: SpinMutex<Vec<JobId>>,
+    default_limits: SpinMutex<TaskLimits>,
+    echo_output: AtomicBool,
+    next_array_id: AtomicU64,
+    arrays: SpinMutex<BTreeMap<ArrayId, JobArray>>,
+}
+
+/// Exit code reported when a task names a program that doesn't exist
+pub const COMMAND_NOT_FOUND_EXIT_CODE: i32 = 127;
+
+/// Program steps a running thread gets before the next thread's turn
+pub const STEPS_PER_SLICE: u64 = 1;
+
+/// A dispatched job, held by each of its threads while they run
+pub struct ActiveJob {
+    pub id: JobId,
+    pub task: Task,
+    main_tid: Tid,
+    state: SpinMutex<JobState>,
+}
+
+struct JobState {
+    /// Threads that haven't ended yet; the last to end reaps the job
+    live_threads: usize,
+    main_exit_code: Option<i32>,
+    /// Set when the job is killed, ending every thread at its next turn
+    killed: Option<Outcome>,
+}
+
+/// A thread of a running job, stepped in turn with the other threads on its hart
+pub struct ActiveThread {
+    pub job: Arc<ActiveJob>,
+    pub ctx: JobContext,
+    /// Stack the thread's steps run on
+    stack: KernelStack,
+}
+
+#[derive(Default)]
+struct ParkedThreads {
+    threads: BTreeMap<Tid, ActiveThread>,
+    /// Threads woken before they finished parking, which go straight back to a run queue
+    woken: BTreeSet<Tid>,
+    /// Threads whose futex wait timed out, to be told so when they next run
+    timed_out: BTreeSet<Tid>,
+}
+
+/// What a hart did with its turn at the run queue
+enum Slice {
+    /// No thread was waiting for a time slice
+    Idle,
+    Ran,
+    /// The thread was the last of its job to end, and the job was reaped
+    Finished,
+}
+
+/// How a thread's time slice ended
+enum SliceEnd {
+    Preempted,
+    Blocked,
+    /// The thread called exit
+    Exited(i32),
+    /// The thread's job was killed
+    Killed,
+}
+
+/// How a single run of a job ended
+enum Outcome {
+    Exited(i32),
+    Fault(String),
+    Killed,
+    TimedOut,
+    NotFound,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobControlError {
+    UnknownJob,
+    AlreadyFinished,
+    /// Only jobs waiting in the queue can be held
+    NotPending,
+    NotHeld,
+}
+
+/// Why a submission was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubmitError {
+    /// Can never be placed: needs more than the machine, its account's quota
+    /// or its reservation provides
+    ExceedsCapacity,
+    /// Requests no CPU or no memory
+    ZeroResources,
+    /// Names a program that isn't built into the kernel
+    UnknownExecutable,
+    /// The account already has as many jobs waiting as its quota allows
+    QueueFull,
+    /// Depends on a job that doesn't exist
+    InvalidDependency(JobId),
+    /// Its affinity names none of the harts the kernel can run
+    NoEligibleHart,
+    /// An array allowing no children to run at once
+    ZeroConcurrency,
+    /// An array with more children than `MAX_ARRAY_SIZE`
+    ArrayTooLarge,
+    /// Names a generic resource the machine doesn't have
+    UnknownResource(String),
+}
+
+impl fmt::Display for SubmitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SubmitError::ExceedsCapacity => write!(f, "requires more resources than can ever be allocated"),
+            SubmitError::ZeroResources => write!(f, "requests no CPU or no memory"),
+            SubmitError::UnknownExecutable => write!(f, "command not found"),
+            SubmitError::QueueFull => write!(f, "account is at its queued-job quota"),
+            SubmitError::InvalidDependency(job) => write!(f, "depends on unknown job {}", job),
+            SubmitError::NoEligibleHart => write!(f, "affinity allows no hart"),
+            SubmitError::ZeroConcurrency => write!(f, "array allows no children to run at once"),
+            SubmitError::ArrayTooLarge => write!(f, "array has more than {} children", MAX_ARRAY_SIZE),
+            SubmitError::UnknownResource(name) => write!(f, "unknown resource `{}`", name),
+        }
+    }
 }
 
 impl BatchSystem {
     pub fn new(total_resources: ResourceRequirements) -> Self {
-        let resource_manager = Arc::new(Mutex::new(ResourceManager::new(total_resources.clone())));
+        let resource_manager = Arc::new(SpinMutex::new(ResourceManager::new(total_resources)));
         BatchSystem {
-            scheduler: Mutex::new(Scheduler::new(total_resources)),
+            scheduler: IrqSafeMutex::new(Scheduler::new(Arc::clone(&resource_manager))),
             resource_manager,
+            running: SpinMutex::new(BTreeMap::new()),
+            parked: SpinMutex::new(ParkedThreads::default()),
+            jobs_reaped: AtomicU64::new(0),
+            next_job_id: AtomicU64::new(1),
+            jobs: SpinMutex::new(JobTable::new()),
+            blocked: SpinMutex::new(Vec::new()),
+            cancel_requests: SpinMutex::new(Vec::new()),
+            default_limits: SpinMutex::new(TaskLimits::unlimited()),
+            echo_output: AtomicBool::new(true),
+            next_array_id: AtomicU64::new(1),
+            arrays: SpinMutex::new(BTreeMap::new()),
         }
     }
 
-    pub fn submit_task(&self, task: Task) {
-        let mut scheduler = self.scheduler.lock();
-        scheduler.add_task(task);
-        println!("[BATCH] Task submitted: {:?}", scheduler.get_last_task());
+    pub fn submit_task(&self, task: Task) -> Result<JobId, SubmitError> {
+        let checked = self.validate(&task).and_then(|()| {
+            match self.over_queue_quota(&task.account) {
+                Some(QuotaAction::Reject) => Err(SubmitError::QueueFull),
+                _ => Ok(()),
+            }
+        });
+        if let Err(err) = checked {
+            println!("[BATCH] Rejecting {}: {}", task.executable, err);
+            return Err(err);
+        }
+
+        let id = JobId(self.next_job_id.fetch_add(1, Ordering::SeqCst));
+        self.jobs.lock().insert(id, task.clone(), TaskStatus::Pending, timer::ticks());
+        self.admit(id, task);
+        Ok(id)
     }
 
-    pub fn run(&self) {
-        println!("\n[BATCH] Starting batch system execution...");
-        let mut completed_tasks = 0;
-        let mut failed_tasks = 0;
+    fn validate(&self, task: &Task) -> Result<(), SubmitError> {
+        let requirements = &task.resource_requirements;
+        if requirements.cpu == 0 || requirements.memory == 0 {
+            return Err(SubmitError::ZeroResources);
+        }
+        if programs::lookup(&task.executable).is_none() {
+            return Err(SubmitError::UnknownExecutable);
+        }
+        if !(0..smp::MAX_HARTS).any(|hart| task.affinity.contains(hart)) {
+            return Err(SubmitError::NoEligibleHart);
+        }
+        let resource_manager = self.resource_manager.lock();
+        let max_claim = task.max_claim();
+        let unknown = max_claim
+            .generic
+            .keys()
+            .find(|name| !resource_manager.total_resources.generic.contains_key(*name));
+        if let Some(name) = unknown {
+            return Err(SubmitError::UnknownResource(name.clone()));
+        }
+        if !resource_manager.can_ever_allocate(task, timer::ticks()) {
+            return Err(SubmitError::ExceedsCapacity);
+        }
+        drop(resource_manager);
 
-        loop {
-            // Print current status
-            self.get_status().print();
+        // Jobs may only depend on jobs submitted before them; `admit` still
+        // checks for cycles in case that ever stops holding
+        let jobs = self.jobs.lock();
+        match task.dependencies.iter().find(|dep| jobs.get(dep.job).is_none()) {
+            Some(dep) => Err(SubmitError::InvalidDependency(dep.job)),
+            None => Ok(()),
+        }
+    }
+
+    /// Submits one job per index in `range`, each a copy of `template` with
+    /// `%a` in its arguments replaced by the index. At most `max_concurrent`
+    /// of the children are queued or running at once.
+    pub fn submit_array(
+        &self,
+        template: Task,
+        range: Range<u32>,
+        max_concurrent: Option<usize>,
+    ) -> Result<ArrayId, SubmitError> {
+        let valid = match max_concurrent {
+            Some(0) => Err(SubmitError::ZeroConcurrency),
+            _ if range.len() > MAX_ARRAY_SIZE => Err(SubmitError::ArrayTooLarge),
+            _ => self.validate(&template),
+        };
+        if let Err(err) = valid {
+            println!("[BATCH] Rejecting array of {}: {}", template.executable, err);
+            return Err(err);
+        }
+
+        let array_id = ArrayId(self.next_array_id.fetch_add(1, Ordering::SeqCst));
+        let mut array = JobArray::new(array_id, max_concurrent);
+        let now = timer::ticks();
+
+        for index in range {
+            let id = JobId(self.next_job_id.fetch_add(1, Ordering::SeqCst));
+            let mut task = template.clone();
+            task.arguments = substitute_index(&template.arguments, index);
+
+            let mut jobs = self.jobs.lock();
+            jobs.insert(id, task.clone(), TaskStatus::Pending, now);
+            jobs.set_array_task(id, ArrayTask { array: array_id, index });
+            array.add_throttled(id, task);
+        }
+
+        println!("[BATCH] Array {} submitted: {} x {} (max concurrent: {:?})",
+            array_id, array.jobs.len(), template.executable, max_concurrent);
+        self.arrays.lock().insert(array_id, array);
+        self.release_array_tasks();
+        Ok(array_id)
+    }
 
+    /// Queues a job whose record already exists, or blocks it on its dependencies
+    fn admit(&self, id: JobId, mut task: Task) {
+        if self.creates_cycle(id, &task) {
+            println!("[BATCH] Rejecting job {}: dependency cycle detected", id);
+            let reason = Some(String::from("dependency cycle"));
+            self.jobs.lock().mark_finished(id, TaskStatus::Cancelled, timer::ticks(), None, reason);
+            return;
+        }
+
+        // Array children are only checked against the quota once the throttle lets them through
+        let over_quota = self.over_queue_quota(&task.account);
+        if over_quota == Some(QuotaAction::Reject) {
+            println!("[BATCH] Rejecting job {}: account {} is at its queued-job quota", id, task.account);
+            let reason = Some(format!("account {} over queued-job quota", task.account));
+            self.jobs.lock().mark_finished(id, TaskStatus::Cancelled, timer::ticks(), None, reason);
+            return;
+        }
+
+        if task.dependencies.is_empty() {
+            self.jobs.lock().set_status(id, TaskStatus::Pending);
             let mut scheduler = self.scheduler.lock();
-            if let Some(task) = scheduler.schedule_next_task() {
-                drop(scheduler);
-
-                println!("\n[BATCH] Executing task: {:?}", task);
-                let mut resource_manager = self.resource_manager.lock();
-                if resource_manager.allocate_resources(&task.resource_requirements) {
-                    drop(resource_manager);
-
-                    let status = self.execute_task(&task);
-                    match status {
-                        TaskStatus::Completed => completed_tasks += 1,
-                        TaskStatus::Failed => failed_tasks += 1,
-                        _ => {}
+            scheduler.add_task(id, task);
+            println!("[BATCH] Job {} submitted: {:?}", id, scheduler.get_last_task());
+            if over_quota.is_some() {
+                println!("[BATCH] Holding job {}: account is at its queued-job quota", id);
+                scheduler.set_held(id, true);
+            }
+        } else {
+            task.status = TaskStatus::Blocked;
+            println!("[BATCH] Job {} submitted, waiting on dependencies: {:?}", id, task);
+            self.jobs.lock().set_status(id, TaskStatus::Blocked);
+            self.blocked.lock().push((id, task));
+            self.resolve_dependencies();
+        }
+    }
+
+    /// Follows dependency edges through blocked jobs looking for a path back to `id`
+    fn creates_cycle(&self, id: JobId, task: &Task) -> bool {
+        let blocked = self.blocked.lock();
+        let mut stack: Vec<JobId> = task.dependencies.iter().map(|dep| dep.job).collect();
+        let mut visited = Vec::new();
+
+        while let Some(job) = stack.pop() {
+            if job == id {
+                return true;
+            }
+            if visited.contains(&job) {
+                continue;
+            }
+            visited.push(job);
+            if let Some((_, blocked_task)) = blocked.iter().find(|(blocked_id, _)| *blocked_id == job) {
+                stack.extend(blocked_task.dependencies.iter().map(|dep| dep.job));
+            }
+        }
+        false
+    }
+
+    /// Lets throttled array children through while their array is under its
+    /// concurrency limit
+    fn release_array_tasks(&self) {
+        loop {
+            let mut arrays = self.arrays.lock();
+            let jobs = self.jobs.lock();
+            let released = arrays.values_mut().find_map(|array| {
+                let active = array
+                    .released()
+                    .filter(|id| jobs.get_status(**id).is_some_and(|status| !status.is_terminal()))
+                    .count();
+                array.release_next(active)
+            });
+            drop(jobs);
+            drop(arrays);
+
+            match released {
+                Some((id, task)) => self.admit(id, task),
+                None => break,
+            }
+        }
+    }
+
+    /// What the account's quota does with another waiting job, if it already
+    /// has as many queued or blocked jobs as the quota allows
+    fn over_queue_quota(&self, account: &str) -> Option<QuotaAction> {
+        let blocked = self.blocked.lock();
+        self.quota_exceeded(account, &blocked)
+    }
+
+    /// `over_queue_quota` for a caller already holding the blocked list
+    fn quota_exceeded(&self, account: &str, blocked: &[(JobId, Task)]) -> Option<QuotaAction> {
+        let quota = self.resource_manager.lock().get_quota(account);
+        let max = quota.max_queued_jobs?;
+        let blocked = blocked.iter().filter(|(_, task)| task.account == account).count();
+        let queued = blocked + self.scheduler.lock().get_queue_length_for(account);
+        (queued >= max).then_some(quota.over_queue_limit)
+    }
+
+    pub fn get_array_status(&self, id: ArrayId) -> Option<ArrayStatus> {
+        let arrays = self.arrays.lock();
+        let jobs = self.jobs.lock();
+        let array = arrays.get(&id)?;
+
+        let mut status = ArrayStatus {
+            total: array.jobs.len(),
+            ..ArrayStatus::default()
+        };
+        for job in &array.jobs {
+            match jobs.get_status(*job) {
+                Some(TaskStatus::Pending) => status.pending += 1,
+                Some(TaskStatus::Blocked) => status.blocked += 1,
+                Some(TaskStatus::Running) => status.running += 1,
+                Some(TaskStatus::Completed) => status.completed += 1,
+                Some(TaskStatus::Failed | TaskStatus::TimedOut) => status.failed += 1,
+                Some(TaskStatus::Cancelled) => status.cancelled += 1,
+                None => {}
+            }
+        }
+        Some(status)
+    }
+
+    pub fn get_job_status(&self, id: JobId) -> Option<TaskStatus> {
+        self.jobs.lock().get_status(id).cloned()
+    }
+
+    /// Looks up a job by ID, including jobs that have already finished
+    pub fn get_job(&self, id: JobId) -> Option<JobRecord> {
+        self.jobs.lock().get(id).cloned()
+    }
+
+    pub fn list_jobs(&self) -> Vec<JobRecord> {
+        self.jobs.lock().records()
+    }
+
+    /// Output captured from the job's most recent run
+    pub fn get_job_output(&self, id: JobId) -> Option<JobOutput> {
+        self.jobs.lock().get(id).map(|job| job.output.clone())
+    }
+
+    /// Whether job output is also copied to the console as it is written
+    pub fn set_echo_output(&self, echo: bool) {
+        self.echo_output.store(echo, Ordering::Relaxed);
+    }
+
+    /// Queues blocked jobs whose dependencies are all met and cancels the ones
+    /// whose dependencies can no longer be met, repeating until nothing changes
+    /// so cancellations cascade down the graph.
+    fn resolve_dependencies(&self) {
+        loop {
+            let mut changed = false;
+            let mut blocked = self.blocked.lock();
+            let mut jobs = self.jobs.lock();
+
+            let mut idx = 0;
+            while idx < blocked.len() {
+                let (id, task) = &blocked[idx];
+                let mut ready = true;
+                let mut impossible = None;
+                for dep in &task.dependencies {
+                    match dep.evaluate(jobs.get_status(dep.job)) {
+                        Some(true) => {}
+                        Some(false) => impossible = impossible.or(Some(dep.job)),
+                        None => ready = false,
                     }
+                }
 
-                    let mut resource_manager = self.resource_manager.lock();
-                    resource_manager.release_resources(&task.resource_requirements);
+                if let Some(prerequisite) = impossible {
+                    println!("[BATCH] Cancelling job {} ({}): dependency can no longer be satisfied",
+                        id, task.executable);
+                    let reason = format!("dependency on job {} can no longer be satisfied", prerequisite);
+                    jobs.mark_finished(*id, TaskStatus::Cancelled, timer::ticks(), None, Some(reason));
+                    blocked.remove(idx);
+                    changed = true;
+                } else if ready {
+                    let (id, task) = blocked.remove(idx);
+                    println!("[BATCH] Dependencies met, queueing job {} ({})", id, task.executable);
+                    jobs.set_status(id, TaskStatus::Pending);
+                    // It was only checked against the quota when blocked, so
+                    // it may join a queue its account has since filled
+                    let hold = self.quota_exceeded(&task.account, &blocked) == Some(QuotaAction::Hold);
+                    let mut scheduler = self.scheduler.lock();
+                    scheduler.add_task(id, task);
+                    if hold {
+                        println!("[BATCH] Holding job {}: account is at its queued-job quota", id);
+                        scheduler.set_held(id, true);
+                    }
+                    changed = true;
                 } else {
-                    println!("[BATCH] Failed to allocate resources for task: {:?}", task);
-                    println!("[BATCH] Required resources: CPU={}, Memory={}KB",
-                        task.resource_requirements.cpu,
-                        task.resource_requirements.memory);
-                    failed_tasks += 1;
+                    idx += 1;
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    fn finish_job(&self, id: JobId, status: TaskStatus, exit_code: Option<i32>, failure_reason: Option<String>) {
+        self.jobs.lock().mark_finished(id, status, timer::ticks(), exit_code, failure_reason);
+        self.resolve_dependencies();
+        self.release_array_tasks();
+    }
+
+    /// Cancels blocked jobs left waiting on jobs that will never finish
+    fn cancel_stranded_jobs(&self) {
+        let mut blocked = self.blocked.lock();
+        let mut jobs = self.jobs.lock();
+        for (id, task) in blocked.drain(..) {
+            println!("[BATCH] Cancelling job {} ({}): dependencies never finished", id, task.executable);
+            let reason = String::from("dependencies never finished");
+            jobs.mark_finished(id, TaskStatus::Cancelled, timer::ticks(), None, Some(reason));
+        }
+    }
+
+    /// Cancels a job. Queued and blocked jobs are removed straight away; a
+    /// running job is killed at its next checkpoint and its resources are
+    /// released by the run loop.
+    pub fn cancel(&self, id: JobId) -> Result<(), JobControlError> {
+        // Read the status under the locks that guard the job leaving its
+        // current state, so a dispatch or dependency release on another hart
+        // can't move it between the check and the removal
+        let mut blocked = self.blocked.lock();
+        let jobs = self.jobs.lock();
+        let status = jobs.get_status(id).cloned().ok_or(JobControlError::UnknownJob)?;
+        match status {
+            TaskStatus::Pending => {
+                let queued = self.scheduler.lock().remove_task(id).is_some();
+                drop(jobs);
+                drop(blocked);
+                // Array children held back by their array's throttle
+                let throttled = !queued
+                    && self.arrays.lock().values_mut().any(|array| array.remove_throttled(id).is_some());
+                if !queued && !throttled {
+                    // Already taken off the queue by a dispatch; kill it once it starts
+                    self.request_kill(id);
+                    return Ok(());
+                }
+            }
+            TaskStatus::Blocked => {
+                blocked.retain(|(blocked_id, _)| *blocked_id != id);
+                drop(jobs);
+                drop(blocked);
+            }
+            TaskStatus::Running => {
+                drop(jobs);
+                drop(blocked);
+                self.request_kill(id);
+                return Ok(());
+            }
+            _ => return Err(JobControlError::AlreadyFinished),
+        }
+
+        println!("[BATCH] Job {} cancelled", id);
+        self.finish_job(id, TaskStatus::Cancelled, None, Some(String::from("cancelled by operator")));
+        Ok(())
+    }
+
+    fn request_kill(&self, id: JobId) {
+        println!("[BATCH] Kill requested for running job {}", id);
+        self.cancel_requests.lock().push(id);
+    }
+
+    /// Keeps a queued job from being scheduled until it is released
+    pub fn hold(&self, id: JobId) -> Result<(), JobControlError> {
+        self.set_held(id, true)
+    }
+
+    pub fn release(&self, id: JobId) -> Result<(), JobControlError> {
+        if !self.scheduler.lock().is_held(id) {
+            return match self.get_job_status(id) {
+                None => Err(JobControlError::UnknownJob),
+                Some(_) => Err(JobControlError::NotHeld),
+            };
+        }
+        self.set_held(id, false)
+    }
+
+    fn set_held(&self, id: JobId, held: bool) -> Result<(), JobControlError> {
+        match self.get_job_status(id) {
+            None => return Err(JobControlError::UnknownJob),
+            Some(status) if status.is_terminal() => return Err(JobControlError::AlreadyFinished),
+            Some(TaskStatus::Pending) => {}
+            Some(_) => return Err(JobControlError::NotPending),
+        }
+
+        if !self.scheduler.lock().set_held(id, held) {
+            // Throttled array children aren't in the scheduler queue yet
+            return Err(JobControlError::NotPending);
+        }
+        println!("[BATCH] Job {} {}", id, if held { "held" } else { "released" });
+        Ok(())
+    }
+
+    fn take_cancel_request(&self, id: JobId) -> bool {
+        let mut cancel_requests = self.cancel_requests.lock();
+        match cancel_requests.iter().position(|requested| *requested == id) {
+            Some(idx) => {
+                cancel_requests.remove(idx);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Limits applied to tasks that don't set their own
+    pub fn set_default_limits(&self, limits: TaskLimits) {
+        *self.default_limits.lock() = limits;
+    }
+
+    pub fn set_aging_policy(&self, aging_policy: AgingPolicy) {
+        self.scheduler.lock().set_aging_policy(aging_policy);
+    }
+
+    pub fn set_quota(&self, account: &str, quota: AccountQuota) {
+        println!("[BATCH] Quota for account {}: {:?}", account, quota);
+        self.resource_manager.lock().set_quota(account, quota);
+    }
+
+    pub fn set_fair_share_policy(&self, policy: FairSharePolicy) {
+        self.resource_manager.lock().set_fair_share_policy(policy);
+    }
+
+    pub fn set_allocation_policy(&self, policy: AllocationPolicy) {
+        println!("[BATCH] Allocation policy: {:?}", policy);
+        self.resource_manager.lock().set_allocation_policy(policy);
+    }
+
+    /// Sets capacity aside for the jobs of `reservation.owner` that name it.
+    /// Returns false if the reservation clashes with an existing one.
+    pub fn create_reservation(&self, reservation: AdvanceReservation) -> bool {
+        let description = format!("{} for {}: ticks {}-{} ({})",
+            reservation.name, reservation.owner, reservation.start_tick,
+            reservation.end_tick(), reservation.resources);
+        if self.resource_manager.lock().create_reservation(reservation) {
+            println!("[BATCH] Reservation {}", description);
+            true
+        } else {
+            println!("[BATCH] Rejecting reservation {}: overlaps existing reservations", description);
+            false
+        }
+    }
+
+    pub fn run(&self) {
+        println!("\n[BATCH] Starting batch system execution...");
+
+        let mut changed = true;
+        let mut dispatch_due = true;
+        let mut reaped = self.jobs_reaped.load(Ordering::SeqCst);
+        let mut queued = 0;
+        let mut next_dispatch = None;
+        loop {
+            // Print current status whenever jobs have started or finished
+            if changed {
+                self.get_status().print();
+            }
+            changed = false;
+            // Scheduling allocates, so only look again once something could start
+            if dispatch_due {
+                changed = self.dispatch();
+                let scheduler = self.scheduler.lock();
+                queued = scheduler.get_queue_length();
+                next_dispatch = scheduler.next_wakeup(timer::ticks());
+            }
+
+            if !self.running.lock().is_empty() {
+                load_balancer::balance(timer::ticks());
+                self.wake_doomed_jobs();
+                self.expire_futex_waits();
+                self.report_deadlock();
+                // Run jobs alongside the other harts, or wait for them to finish theirs
+                if let Slice::Idle = self.run_time_slice() {
+                    core::hint::spin_loop();
                 }
+                let now_reaped = self.jobs_reaped.load(Ordering::SeqCst);
+                changed |= now_reaped != reaped;
+                reaped = now_reaped;
+                // A job finished, a job was queued, or a queued job stopped waiting
+                dispatch_due = changed
+                    || self.scheduler.lock().get_queue_length() != queued
+                    || next_dispatch.is_some_and(|wakeup| timer::ticks() >= wakeup);
+                continue;
+            }
+
+            // Tasks larger than the whole machine would block the queue forever
+            let unsatisfiable = self.scheduler.lock().take_unsatisfiable_tasks();
+            if !unsatisfiable.is_empty() {
+                for (id, task) in unsatisfiable {
+                    println!("[BATCH] Job {} can never be scheduled: {}", id, task.executable);
+                    println!("[BATCH] Required resources: {}", task.resource_requirements);
+                    let reason = String::from("requires more resources than the machine, its account's quota or its reservation allows");
+                    self.finish_job(id, TaskStatus::Failed, None, Some(reason));
+                }
+                // Failing a task may have queued its dependents
+                changed = true;
+                dispatch_due = true;
+                continue;
+            }
+
+            let now = timer::ticks();
+            let scheduler = self.scheduler.lock();
+            let tasks_remaining = scheduler.get_queue_length();
+            let next_wakeup = scheduler.next_wakeup(now);
+            drop(scheduler);
+
+            if let Some(wakeup) = next_wakeup {
+                // Nothing can run until a retry finishes backing off or a reservation changes
+                while timer::ticks() < wakeup {
+                    unsafe { riscv::asm::wfi() };
+                }
+                changed = true;
+                dispatch_due = true;
+                continue;
+            }
+
+            if tasks_remaining > 0 {
+                println!("\n[BATCH] {} tasks remain but none can be scheduled.", tasks_remaining);
             } else {
                 println!("\n[BATCH] No more tasks to execute.");
-                println!("[BATCH] Summary:");
-                println!("  - Completed tasks: {}", completed_tasks);
-                println!("  - Failed tasks: {}", failed_tasks);
-                println!("  - Total tasks: {}", completed_tasks + failed_tasks);
+            }
+            self.cancel_stranded_jobs();
+            self.print_summary();
+            break;
+        }
+    }
+
+    /// Starts queued jobs until the scheduler finds nothing more that fits,
+    /// returning whether any job was started
+    fn dispatch(&self) -> bool {
+        let mut dispatched = false;
+        loop {
+            let running: Vec<RunningTask> = self.running.lock().values().cloned().collect();
+            if running.len() >= watchdog::MAX_WATCHED_JOBS {
+                break;
+            }
+            let Some((id, task)) = self.scheduler.lock().schedule_next_task(&running) else {
+                break;
+            };
+            if !self.start_job(id, task) {
                 break;
             }
+            dispatched = true;
         }
+        dispatched
     }
 
-    fn execute_task(&self, task: &Task) -> TaskStatus {
+    /// Starts a job the scheduler picked, returning false if it had to be
+    /// requeued because every watchdog slot or kernel stack was taken
+    fn start_job(&self, id: JobId, task: Task) -> bool {
+        let now = timer::ticks();
+        // A job naming no program is reaped at once, so needs no stack
+        let program = programs::lookup(&task.executable);
+        let stack = program.and_then(|program| KernelStack::new(program.step));
+        if program.is_some() && stack.is_none() {
+            println!("[BATCH] No kernel stack free for job {}, requeueing", id);
+            self.requeue_job(id, task);
+            return false;
+        }
+        let limits = task.limits.or(&self.default_limits.lock());
+        if !watchdog::start(id, &limits, now) {
+            // Another hart took the last slot after this one counted the running jobs
+            println!("[BATCH] No watchdog slot free for job {}, requeueing", id);
+            self.requeue_job(id, task);
+            return false;
+        }
+
+        println!("\n[BATCH] Executing job {}: {:?}", id, task);
         println!("[TASK] Starting execution: {}", task.executable);
         println!("[TASK] Priority: {}", task.priority);
-        println!("[TASK] Resources: CPU={}, Memory={}KB",
-            task.resource_requirements.cpu,
-            task.resource_requirements.memory);
+        println!("[TASK] Resources: {}", task.resource_requirements);
+        self.jobs.lock().mark_started(id, now);
+
+        let ctx = JobContext::new(
+            id,
+            task.arguments.clone(),
+            task.resource_requirements.memory as usize * 1024,
+            self.echo_output.load(Ordering::Relaxed),
+            Arc::clone(&self.resource_manager),
+        );
+        self.running.lock().insert(id, RunningTask::new(&task, now));
+
+        let job = Arc::new(ActiveJob {
+            id,
+            main_tid: ctx.tid,
+            task,
+            state: SpinMutex::new(JobState {
+                live_threads: 1,
+                main_exit_code: None,
+                killed: None,
+            }),
+        });
+        let Some(stack) = stack else {
+            println!("[TASK] No such program: {}", job.task.executable);
+            self.reap(&job, &ctx, Outcome::NotFound);
+            return true;
+        };
+        let hart = load_balancer::place(ActiveThread { job, ctx, stack });
+        println!("[SMP] Job {} queued on hart {}", id, hart);
+        true
+    }
+
+    /// Puts a job the scheduler picked back in the queue without starting it
+    fn requeue_job(&self, id: JobId, task: Task) {
+        self.resource_manager.lock().release_resources(id, &task);
+        self.scheduler.lock().add_task(id, task);
+    }
+
+    /// Runs one time slice on the calling hart, returning false if no thread
+    /// was waiting for one. Called from every hart's idle loop.
+    pub fn run_on_hart(&self) -> bool {
+        !matches!(self.run_time_slice(), Slice::Idle)
+    }
+
+    /// Gives the thread at the front of the round-robin a time slice on the
+    /// calling hart, then sends it to the back, parks it or ends it
+    fn run_time_slice(&self) -> Slice {
+        let cpu = percpu::this();
+        let next = cpu.run_queue.lock().pop_front();
+        let Some(mut thread) = next.or_else(load_balancer::steal) else {
+            return Slice::Idle;
+        };
+        cpu.slices.fetch_add(1, Ordering::Relaxed);
+
+        watchdog::set_on_cpu(Some(thread.job.id));
+        let end = self.step_thread(&mut thread);
+        watchdog::set_on_cpu(None);
+
+        let exit_code = match end {
+            SliceEnd::Preempted => {
+                cpu.run_queue.lock().push_back(thread);
+                return Slice::Ran;
+            }
+            SliceEnd::Blocked => {
+                self.park(thread);
+                return Slice::Ran;
+            }
+            SliceEnd::Exited(code) => Some(code),
+            SliceEnd::Killed => None,
+        };
+        match self.end_thread(thread, exit_code) {
+            true => Slice::Finished,
+            false => Slice::Ran,
+        }
+    }
+
+    /// Steps a thread for up to `STEPS_PER_SLICE` steps, queueing any threads
+    /// it creates. Kills and time limits are checked between steps; a fault
+    /// in any thread kills the whole job.
+    fn step_thread(&self, thread: &mut ActiveThread) -> SliceEnd {
+        for _ in 0..STEPS_PER_SLICE {
+            if self.check_killed(&thread.job) {
+                return SliceEnd::Killed;
+            }
+
+            thread.stack.run(&mut thread.ctx);
+            thread.ctx.steps += 1;
+            self.wake(core::mem::take(&mut thread.ctx.wake));
+            for spawned in core::mem::take(&mut thread.ctx.spawned) {
+                self.spawn(thread, spawned);
+            }
+            match thread.ctx.exit.take() {
+                Some(ExitReason::Fault(description)) => {
+                    println!("[TASK] {} killed by fault in thread {}: {}",
+                        thread.job.task.executable, thread.ctx.tid, description);
+                    self.kill(&thread.job, Outcome::Fault(description));
+                    return SliceEnd::Killed;
+                }
+                Some(ExitReason::Exited(code)) => return SliceEnd::Exited(code),
+                None if thread.ctx.is_blocked() => return SliceEnd::Blocked,
+                None => {}
+            }
+        }
+        SliceEnd::Preempted
+    }
+
+    /// Returns whether `job` has been killed, first killing it if the
+    /// operator asked to or it has run out of time
+    fn check_killed(&self, job: &ActiveJob) -> bool {
+        if job.state.lock().killed.is_some() {
+            return true;
+        }
+        if self.take_cancel_request(job.id) {
+            println!("[TASK] Killed: {}", job.task.executable);
+            self.kill(job, Outcome::Killed);
+            return true;
+        }
+        if let Some(overrun) = watchdog::overrun(job.id, timer::ticks()) {
+            println!("[TASK] Killed: {} ({})", job.task.executable, describe_overrun(&overrun));
+            self.kill(job, Outcome::TimedOut);
+            return true;
+        }
+        false
+    }
+
+    /// Marks `job` killed, unless it already was. Its threads end at their next turn.
+    fn kill(&self, job: &ActiveJob, outcome: Outcome) {
+        job.state.lock().killed.get_or_insert(outcome);
+    }
+
+    /// Queues a thread created by `parent`
+    fn spawn(&self, parent: &ActiveThread, spawned: SpawnedThread) {
+        parent.job.state.lock().live_threads += 1;
+        let ctx = parent.ctx.spawn(&spawned);
+        let hart = load_balancer::place(ActiveThread {
+            job: Arc::clone(&parent.job),
+            ctx,
+            stack: spawned.stack,
+        });
+        println!("[TASK] Job {} created thread {} on hart {}", parent.job.id, spawned.tid, hart);
+    }
+
+    /// Ends a thread, recording its exit code for `waittid` and waking
+    /// threads waiting there for it. The last thread of a job to end reaps
+    /// it, returning true.
+    fn end_thread(&self, thread: ActiveThread, exit_code: Option<i32>) -> bool {
+        let ActiveThread { job, ctx, .. } = thread;
+        // Hand on any primitives the thread held, and forget wake-ups it never used
+        let woken = SYNC.lock().release_thread(ctx.tid);
+        self.wake(woken);
+        FUTEXES.lock().release_thread(ctx.tid);
+        self.resource_manager.lock().cancel_request(ctx.tid);
+        let mut parked = self.parked.lock();
+        parked.woken.remove(&ctx.tid);
+        parked.timed_out.remove(&ctx.tid);
+        drop(parked);
+        let mut shared = ctx.shared.lock();
+        if let Some(code) = exit_code {
+            shared.threads.insert(ctx.tid, Some(code));
+        }
+        let joiners = shared.joiners.remove(&ctx.tid).unwrap_or_default();
+        for waiters in shared.joiners.values_mut() {
+            waiters.retain(|&waiter| waiter != ctx.tid);
+        }
+        drop(shared);
+        self.wake(joiners);
+
+        let mut state = job.state.lock();
+        if ctx.tid == job.main_tid {
+            state.main_exit_code = exit_code;
+        }
+        state.live_threads -= 1;
+        if state.live_threads > 0 {
+            return false;
+        }
+        let outcome = match state.killed.take() {
+            Some(outcome) => outcome,
+            None => {
+                let code = state.main_exit_code.unwrap_or(0);
+                println!("[TASK] Execution completed: {} (exit code {})", job.task.executable, code);
+                Outcome::Exited(code)
+            }
+        };
+        drop(state);
+        self.reap(&job, &ctx, outcome);
+        true
+    }
+
+    /// Takes a thread blocked in a syscall off the run queues until it is woken
+    fn park(&self, thread: ActiveThread) {
+        let mut parked = self.parked.lock();
+        if parked.woken.remove(&thread.ctx.tid) {
+            drop(parked);
+            self.requeue(thread);
+            return;
+        }
+        parked.threads.insert(thread.ctx.tid, thread);
+    }
+
+    /// Puts parked threads back on a run queue, remembering any that haven't
+    /// finished parking yet
+    fn wake(&self, tids: Vec<Tid>) {
+        for tid in tids {
+            let thread = {
+                let mut parked = self.parked.lock();
+                let thread = parked.threads.remove(&tid);
+                if thread.is_none() {
+                    parked.woken.insert(tid);
+                }
+                thread
+            };
+            if let Some(thread) = thread {
+                self.requeue(thread);
+            }
+        }
+    }
+
+    fn requeue(&self, mut thread: ActiveThread) {
+        thread.ctx.blocked_on = None;
+        thread.ctx.timed_out = self.parked.lock().timed_out.remove(&thread.ctx.tid);
+        load_balancer::place(thread);
+    }
+
+    /// Wakes parked threads whose job has been killed or run out of time, so
+    /// their next turn ends them
+    fn wake_doomed_jobs(&self) {
+        let now = timer::ticks();
+        let doomed: Vec<Tid> = self
+            .parked
+            .lock()
+            .threads
+            .values()
+            .filter(|thread| {
+                let job = &thread.job;
+                job.state.lock().killed.is_some()
+                    || self.cancel_requests.lock().contains(&job.id)
+                    || watchdog::overrun(job.id, now).is_some()
+            })
+            .map(|thread| thread.ctx.tid)
+            .collect();
+        self.wake(doomed);
+    }
+
+    /// Wakes threads whose futex wait has timed out
+    fn expire_futex_waits(&self) {
+        let now = timer::ticks();
+        if !futex::deadline_passed(now) {
+            return;
+        }
+        let expired = FUTEXES.lock().expire(now);
+        self.parked.lock().timed_out.extend(expired.iter().copied());
+        self.wake(expired);
+    }
 
-        // Simulate work based on task priority
-        let iterations = task.priority * 50_000; // Reduced for faster execution
-        for i in 0..iterations {
-            if i % 5_000 == 0 {
-                println!("[TASK] Progress: {}%", (i * 100) / iterations);
+    /// Reports jobs deadlocked waiting on each other's resources, once each
+    /// time the set of waiting requests changes. Only in `Detect` mode; the
+    /// jobs are left for the operator or their time limits to kill.
+    fn report_deadlock(&self) {
+        let mut resource_manager = self.resource_manager.lock();
+        let Some(deadlocked) = resource_manager.detect_deadlock() else {
+            return;
+        };
+        println!("[BATCH] Deadlock: {} jobs are waiting on resources only they hold", deadlocked.len());
+        for id in deadlocked {
+            if let Some(held) = resource_manager.allocated_to(id) {
+                println!("[BATCH]   job {} holds {}", id, held);
             }
-            core::hint::spin_loop();
+            for (tid, request) in resource_manager.pending_requests(id) {
+                println!("[BATCH]   job {} thread {} waits for {}", id, tid, request);
+            }
+        }
+    }
+
+    /// Releases a finished job's resources and records how it ended, queueing
+    /// it again if its retry policy allows. `ctx` is its last thread's.
+    fn reap(&self, job: &ActiveJob, ctx: &JobContext, outcome: Outcome) {
+        let id = job.id;
+        let mut task = job.task.clone();
+        let now = timer::ticks();
+        let overrun = watchdog::overrun(id, now);
+        let usage = watchdog::stop(id, now);
+
+        let mut resource_manager = self.resource_manager.lock();
+        if !resource_manager.release_resources(id, &task) {
+            println!("[BATCH] Error: job {} released more resources than it held ({})",
+                id, task.resource_requirements);
+        }
+        // Charge the account for every CPU it held, whether or not the job used it
+        resource_manager.record_usage(&task.account, usage.wall_ticks * task.resource_requirements.cpu as u64);
+        let granted = resource_manager.grant_pending();
+        drop(resource_manager);
+        self.wake(granted);
+        let (status, exit_code, reason, failure) = match outcome {
+            Outcome::Exited(0) => (TaskStatus::Completed, Some(0), None, RetryOn::empty()),
+            Outcome::Exited(code) => (
+                TaskStatus::Failed,
+                Some(code),
+                Some(format!("exited with code {}", code)),
+                RetryOn::FAILED,
+            ),
+            Outcome::Fault(description) => (
+                TaskStatus::Failed,
+                Some(FAULT_EXIT_CODE),
+                Some(format!("killed by fault: {}", description)),
+                RetryOn::FAULT,
+            ),
+            Outcome::Killed => (
+                TaskStatus::Cancelled,
+                None,
+                Some(String::from("killed by operator")),
+                RetryOn::empty(),
+            ),
+            Outcome::TimedOut => (
+                TaskStatus::TimedOut,
+                None,
+                overrun.as_ref().map(describe_overrun),
+                RetryOn::TIMED_OUT,
+            ),
+            Outcome::NotFound => (
+                TaskStatus::Failed,
+                Some(COMMAND_NOT_FOUND_EXIT_CODE),
+                Some(format!("{}: command not found", task.executable)),
+                RetryOn::empty(),
+            ),
+        };
+        let overrun = overrun.filter(|_| matches!(status, TaskStatus::TimedOut));
+
+        let mut jobs = self.jobs.lock();
+        let mut shared = ctx.shared.lock();
+        jobs.record_usage(id, usage.cpu_ticks, shared.peak_memory.div_ceil(1024) as u64, overrun);
+        jobs.record_output(id, core::mem::take(&mut shared.output));
+        drop(shared);
+        let attempts = jobs.record_attempt(id, status.clone(), now, exit_code, reason.clone());
+
+        if task.retry.should_retry(failure, attempts) {
+            let retry_at = now + task.retry.backoff;
+            println!("[BATCH] Job {} attempt {} of {} ended {:?}, retrying at tick {}",
+                id, attempts, task.retry.max_attempts, status, retry_at);
+            jobs.set_status(id, TaskStatus::Pending);
+            drop(jobs);
+            task.status = TaskStatus::Pending;
+            self.scheduler.lock().add_task_after(id, task, retry_at);
+        } else {
+            drop(jobs);
+            self.finish_job(id, status, exit_code, reason);
         }
 
-        println!("[TASK] Execution completed: {}", task.executable);
-        TaskStatus::Completed
+        // Only now, with any retry or dependents queued, may the run loop see the job gone
+        self.running.lock().remove(&id);
+        self.jobs_reaped.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn print_summary(&self) {
+        let total_resources = self.resource_manager.lock().total_resources.clone();
+        let jobs = self.jobs.lock();
+
+        println!("[BATCH] Summary:");
+        println!("  - Completed tasks: {}", jobs.count(|s| matches!(s, TaskStatus::Completed)));
+        println!("  - Failed tasks: {}", jobs.count(|s| matches!(s, TaskStatus::Failed)));
+        println!("  - Timed out tasks: {}", jobs.count(|s| matches!(s, TaskStatus::TimedOut)));
+        println!("  - Cancelled tasks: {}", jobs.count(|s| matches!(s, TaskStatus::Cancelled)));
+        println!("  - Total tasks: {}", jobs.len());
+        for cpu in percpu::all().filter(|cpu| cpu.online.load(Ordering::SeqCst)) {
+            println!("  - Hart {} ran {} time slices over {} ticks and stole {} jobs",
+                cpu.hart_id,
+                cpu.slices.load(Ordering::Relaxed),
+                cpu.ticks.load(Ordering::Relaxed),
+                cpu.steals.load(Ordering::Relaxed));
+        }
+        jobs.print();
+        RunReport::new(&jobs, &total_resources).print();
+    }
+
+    /// Accounting statistics for every job submitted so far
+    pub fn get_run_report(&self) -> RunReport {
+        let total_resources = self.resource_manager.lock().total_resources.clone();
+        RunReport::new(&self.jobs.lock(), &total_resources)
     }
 
     pub fn get_status(&self) -> BatchSystemStatus {
+        let array_ids: Vec<ArrayId> = self.arrays.lock().keys().copied().collect();
+        let arrays = array_ids
+            .into_iter()
+            .filter_map(|id| self.get_array_status(id).map(|status| (id, status)))
+            .collect();
+        let tasks_running = self.running.lock().len();
+        let threads_waiting = self.parked.lock().threads.len();
+        let tasks_blocked = self.blocked.lock().len();
         let scheduler = self.scheduler.lock();
         let resource_manager = self.resource_manager.lock();
 
+        let now = timer::ticks();
+        let queued_tasks = scheduler
+            .task_queue
+            .iter()
+            .map(|queued| QueuedTaskStatus {
+                executable: queued.task.executable.clone(),
+                account: queued.task.account.clone(),
+                base_priority: queued.task.priority,
+                effective_priority: scheduler.effective_priority(queued, now),
+                wait_ticks: queued.wait_ticks(now),
+                held: queued.held,
+            })
+            .collect();
+
         BatchSystemStatus {
             tasks_queued: scheduler.get_queue_length(),
+            tasks_running,
+            threads_waiting,
+            harts_online: smp::online_harts(),
+            tasks_blocked,
             resources_available: resource_manager.get_available_resources().clone(),
+            deadlocked_jobs: resource_manager.get_deadlocked_jobs().to_vec(),
+            advance_reservations: resource_manager
+                .get_reservations()
+                .iter()
+                .filter(|r| !r.has_ended(now))
+                .cloned()
+                .collect(),
             next_task_priority: scheduler.get_next_task_priority(),
+            reservation: scheduler.get_reservation().cloned(),
+            queued_tasks,
+            arrays,
         }
     }
 }
 
+fn describe_overrun(overrun: &Overrun) -> String {
+    let kind = match overrun.kind {
+        LimitKind::WallTime => "wall-time",
+        LimitKind::CpuTime => "CPU-time",
+    };
+    format!("{} limit of {} ticks exceeded (used {} ticks)", kind, overrun.limit, overrun.used)
+}
+
 #[derive(Debug)]
 pub struct BatchSystemStatus {
     pub tasks_queued: usize,
+    pub tasks_running: usize,
+    /// Threads parked on a mutex, semaphore or condition variable
+    pub threads_waiting: usize,
+    pub harts_online: usize,
+    pub tasks_blocked: usize,
     pub resources_available: ResourceRequirements,
+    /// Jobs found waiting on resources only they hold, in `Detect` mode
+    pub deadlocked_jobs: Vec<JobId>,
+    /// Advance reservations that haven't ended yet
+    pub advance_reservations: Vec<AdvanceReservation>,
     pub next_task_priority: Option<u32>,
+    pub reservation: Option<Reservation>,
+    pub queued_tasks: Vec<QueuedTaskStatus>,
+    pub arrays: Vec<(ArrayId, ArrayStatus)>,
+}
+
+#[derive(Debug)]
+pub struct QueuedTaskStatus {
+    pub executable: String,
+    pub account: String,
+    pub base_priority: u32,
+    pub effective_priority: u32,
+    pub wait_ticks: u64,
+    pub held: bool,
 }
 
 impl BatchSystemStatus {
     pub fn print(&self) {
         println!("\n[BATCH] Current System Status:");
         println!("  - Tasks in queue: {}", self.tasks_queued);
+        println!("  - Tasks running: {} (on {} harts, {} threads waiting)",
+            self.tasks_running, self.harts_online, self.threads_waiting);
+        println!("  - Tasks waiting on dependencies: {}", self.tasks_blocked);
         println!("  - Available CPU: {}", self.resources_available.cpu);
         println!("  - Available Memory: {}KB", self.resources_available.memory);
+        for (name, amount) in &self.resources_available.generic {
+            println!("  - Available {}: {}", name, amount);
+        }
+        if !self.deadlocked_jobs.is_empty() {
+            let ids: Vec<String> = self.deadlocked_jobs.iter().map(|id| format!("{}", id)).collect();
+            println!("  - Deadlocked jobs: {}", ids.join(", "));
+        }
         if let Some(priority) = self.next_task_priority {
             println!("  - Next task priority: {}", priority);
         }
+        if let Some(reservation) = &self.reservation {
+            println!("  - Reserved start for {}: tick {}", reservation.executable, reservation.start_tick);
+        }
+        for reservation in &self.advance_reservations {
+            println!("  - Reservation {} for {}: ticks {}-{} ({}; in use {})",
+                reservation.name,
+                reservation.owner,
+                reservation.start_tick,
+                reservation.end_tick(),
+                reservation.resources,
+                reservation.in_use);
+        }
+        for queued in &self.queued_tasks {
+            println!("    * {} [{}] (priority: {} base, {} effective, waiting {} ticks){}",
+                queued.executable,
+                queued.account,
+                queued.base_priority,
+                queued.effective_priority,
+                queued.wait_ticks,
+                if queued.held { " [held]" } else { "" });
+        }
+        for (id, array) in &self.arrays {
+            if !array.is_finished() {
+                array.print(*id);
+            }
+        }
     }
 }
 
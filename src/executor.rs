@@ -1,13 +1,259 @@
-use crate::task::Task;
-use crate::println; // Use your custom println macro
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::irq_lock::SpinMutex;
+use crate::kernel_stack::KernelStack;
+use crate::resource_manager::ResourceManager;
+use crate::sync::WaitChannel;
+use crate::task::{JobId, Tid};
+use crate::print;
 
-pub fn execute_task(task: &Task) {
-    println!("Executing task: {:?}", task);
+pub const FD_STDOUT: usize = 1;
+pub const FD_STDERR: usize = 2;
 
-    // Instead of thread::sleep, use a simple spin loop
-    for _ in 0..1000000 {
-        core::hint::spin_loop();
+/// Bytes kept per output stream; older output is dropped first
+pub const OUTPUT_BUFFER_CAPACITY: usize = 4096;
+
+/// Exit code reported for a job killed by a fault (128 + SIGSEGV)
+pub const FAULT_EXIT_CODE: i32 = 139;
+
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer {
+    data: VecDeque<u8>,
+    dropped: usize,
+}
+
+impl OutputBuffer {
+    pub fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.data.len() == OUTPUT_BUFFER_CAPACITY {
+                self.data.pop_front();
+                self.dropped += 1;
+            }
+            self.data.push_back(byte);
+        }
+    }
+
+    pub fn contents(&self) -> String {
+        let bytes: Vec<u8> = self.data.iter().copied().collect();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// Number of bytes discarded because the buffer was full
+    pub fn dropped(&self) -> usize {
+        self.dropped
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+/// Output captured from a job, retrievable after it finishes
+#[derive(Debug, Clone, Default)]
+pub struct JobOutput {
+    pub stdout: OutputBuffer,
+    pub stderr: OutputBuffer,
+}
+
+#[derive(Debug, Clone)]
+pub enum ExitReason {
+    /// The thread called the exit syscall
+    Exited(i32),
+    /// The program was killed by a fault
+    Fault(String),
+}
+
+/// State shared by every thread of a running program
+pub struct JobShared {
+    pub output: JobOutput,
+    /// Bytes in the program's data segment
+    pub memory: usize,
+    /// Largest the data segment has been
+    pub peak_memory: usize,
+    /// Bytes the data segment may grow to, from the job's memory request
+    pub memory_limit: usize,
+    /// Every thread not yet waited for, with its exit code once it has exited
+    pub threads: BTreeMap<Tid, Option<i32>>,
+    /// Threads parked in `waittid`, by the thread they wait for
+    pub joiners: BTreeMap<Tid, Vec<Tid>>,
+    echo: bool,
+    at_line_start: bool,
+}
+
+/// A thread created by `thread_create`, for the batch system to queue
+pub struct SpawnedThread {
+    pub tid: Tid,
+    /// Stack the thread runs its entry function on
+    pub stack: KernelStack,
+    pub arg: usize,
+}
+
+/// Execution state of one thread of a running program, kept between steps
+/// alongside the thread's kernel stack
+pub struct JobContext {
+    pub id: JobId,
+    pub tid: Tid,
+    pub arguments: Vec<String>,
+    /// Argument given to `thread_create`; 0 for the main thread
+    pub arg: usize,
+    /// Number of times the thread has been stepped
+    pub steps: u64,
+    /// Set when the thread exits or the program faults
+    pub exit: Option<ExitReason>,
+    /// Values the thread keeps between steps
+    pub locals: [usize; 8],
+    /// Set when a syscall blocked the thread; it is parked until woken
+    pub blocked_on: Option<WaitChannel>,
+    /// Whether the thread's last blocking wait ended by timing out
+    pub timed_out: bool,
+    /// Threads the thread's syscalls woke, for the batch system to requeue
+    pub wake: Vec<Tid>,
+    /// Threads created since the last step
+    pub spawned: Vec<SpawnedThread>,
+    pub shared: Arc<SpinMutex<JobShared>>,
+    /// Where the thread requests more resources for its job
+    pub resources: Arc<SpinMutex<ResourceManager>>,
+}
+
+static NEXT_TID: AtomicU64 = AtomicU64::new(1);
+
+pub fn next_tid() -> Tid {
+    Tid(NEXT_TID.fetch_add(1, Ordering::SeqCst))
+}
+
+impl JobShared {
+    fn write(&mut self, id: JobId, fd: usize, bytes: &[u8]) -> bool {
+        match fd {
+            FD_STDOUT => self.output.stdout.write(bytes),
+            FD_STDERR => self.output.stderr.write(bytes),
+            _ => return false,
+        }
+        if self.echo {
+            self.echo_to_console(id, bytes);
+        }
+        true
+    }
+
+    fn echo_to_console(&mut self, id: JobId, bytes: &[u8]) {
+        for line in bytes.split_inclusive(|&byte| byte == b'\n') {
+            if self.at_line_start {
+                print!("[job {}] ", id);
+            }
+            print!("{}", String::from_utf8_lossy(line));
+            self.at_line_start = line.ends_with(b"\n");
+        }
+    }
+}
+
+impl JobContext {
+    /// Context of a program's main thread
+    pub fn new(
+        id: JobId,
+        arguments: Vec<String>,
+        memory_limit: usize,
+        echo: bool,
+        resources: Arc<SpinMutex<ResourceManager>>,
+    ) -> Self {
+        let tid = next_tid();
+        let shared = JobShared {
+            output: JobOutput::default(),
+            memory: 0,
+            peak_memory: 0,
+            memory_limit,
+            threads: BTreeMap::from([(tid, None)]),
+            joiners: BTreeMap::new(),
+            echo,
+            at_line_start: true,
+        };
+        Self::thread(id, tid, arguments, 0, Arc::new(SpinMutex::new(shared)), resources)
+    }
+
+    fn thread(
+        id: JobId,
+        tid: Tid,
+        arguments: Vec<String>,
+        arg: usize,
+        shared: Arc<SpinMutex<JobShared>>,
+        resources: Arc<SpinMutex<ResourceManager>>,
+    ) -> Self {
+        JobContext {
+            id,
+            tid,
+            arguments,
+            arg,
+            steps: 0,
+            exit: None,
+            locals: [0; 8],
+            blocked_on: None,
+            timed_out: false,
+            wake: Vec::new(),
+            spawned: Vec::new(),
+            shared,
+            resources,
+        }
+    }
+
+    /// Context of a new thread of the same program
+    pub fn spawn(&self, thread: &SpawnedThread) -> Self {
+        Self::thread(
+            self.id,
+            thread.tid,
+            self.arguments.clone(),
+            thread.arg,
+            Arc::clone(&self.shared),
+            Arc::clone(&self.resources),
+        )
+    }
+
+    /// Captures output written to `fd`, returning false for an unknown descriptor
+    pub fn write(&mut self, fd: usize, bytes: &[u8]) -> bool {
+        self.shared.lock().write(self.id, fd, bytes)
     }
 
-    println!("Task completed: {:?}", task);
+    /// Resizes the data segment, returning false if `size` is over the limit
+    pub fn set_memory(&mut self, size: usize) -> bool {
+        let mut shared = self.shared.lock();
+        if size > shared.memory_limit {
+            return false;
+        }
+        shared.memory = size;
+        shared.peak_memory = shared.peak_memory.max(size);
+        true
+    }
+
+    pub fn block_on(&mut self, channel: WaitChannel) {
+        self.blocked_on = Some(channel);
+        self.timed_out = false;
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.blocked_on.is_some()
+    }
+
+    pub fn exit(&mut self, code: i32) {
+        self.exit.get_or_insert(ExitReason::Exited(code));
+    }
+
+    pub fn fault(&mut self, description: String) {
+        self.exit.get_or_insert(ExitReason::Fault(description));
+    }
+
+    pub fn has_exited(&self) -> bool {
+        self.exit.is_some()
+    }
+
+    pub fn exit_code(&self) -> Option<i32> {
+        match self.exit {
+            Some(ExitReason::Exited(code)) => Some(code),
+            Some(ExitReason::Fault(_)) => Some(FAULT_EXIT_CODE),
+            None => None,
+        }
+    }
 }
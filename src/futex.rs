@@ -0,0 +1,140 @@
+//! Futexes: wait queues keyed by the address of a 32-bit word, so a program
+//! can build locks that only enter the kernel when they are contended.
+//!
+//! The kernel runs without paging, so the address a program passes is the
+//! physical address of the word, and threads of different jobs waiting on
+//! the same word share a queue. A waiter only parks if the word still holds
+//! the value it expects, checked under the table lock that wakers also take,
+//! so a wake between the program's check and its wait can't be lost.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use crate::irq_lock::SpinMutex;
+use crate::sync::{SyncEffect, WaitChannel};
+use crate::task::Tid;
+
+pub const FUTEX_WAIT: usize = 0;
+pub const FUTEX_WAKE: usize = 1;
+
+struct Waiter {
+    tid: Tid,
+    /// Tick at which the wait times out
+    deadline: Option<u64>,
+}
+
+#[derive(Default)]
+pub struct FutexTable {
+    queues: BTreeMap<usize, VecDeque<Waiter>>,
+}
+
+/// Why a futex operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FutexError {
+    /// The address is null or not 4-byte aligned
+    BadAddress,
+    /// The word no longer held the expected value
+    ValueChanged,
+}
+
+/// Earliest deadline of any waiter, so the run loop can skip the table
+/// until a wait could have timed out
+static NEXT_DEADLINE: AtomicU64 = AtomicU64::new(u64::MAX);
+
+impl FutexTable {
+    pub const fn new() -> Self {
+        FutexTable {
+            queues: BTreeMap::new(),
+        }
+    }
+
+    /// Parks `tid` on the word at `addr` if it still holds `expected`, until
+    /// woken or until tick `deadline`
+    ///
+    /// # Safety
+    ///
+    /// A non-null, aligned `addr` must be the address of a 32-bit word valid
+    /// for reads for the duration of the call.
+    pub unsafe fn wait(
+        &mut self,
+        addr: usize,
+        expected: u32,
+        tid: Tid,
+        deadline: Option<u64>,
+    ) -> Result<SyncEffect, FutexError> {
+        check_address(addr)?;
+        let word = &*(addr as *const AtomicU32);
+        if word.load(Ordering::SeqCst) != expected {
+            return Err(FutexError::ValueChanged);
+        }
+        self.queues.entry(addr).or_default().push_back(Waiter { tid, deadline });
+        if let Some(deadline) = deadline {
+            NEXT_DEADLINE.fetch_min(deadline, Ordering::SeqCst);
+        }
+        Ok(SyncEffect {
+            blocked_on: Some(WaitChannel::Futex(addr)),
+            wake: Vec::new(),
+        })
+    }
+
+    /// Wakes up to `count` threads waiting on the word at `addr`, oldest first
+    pub fn wake(&mut self, addr: usize, count: usize) -> Result<SyncEffect, FutexError> {
+        check_address(addr)?;
+        let Some(queue) = self.queues.get_mut(&addr) else {
+            return Ok(SyncEffect::default());
+        };
+        let wake = queue.drain(..count.min(queue.len())).map(|waiter| waiter.tid).collect();
+        if queue.is_empty() {
+            self.queues.remove(&addr);
+        }
+        Ok(SyncEffect {
+            blocked_on: None,
+            wake,
+        })
+    }
+
+    /// Removes waiters whose timeout has passed, returning them
+    pub fn expire(&mut self, now: u64) -> Vec<Tid> {
+        let mut expired = Vec::new();
+        let mut next = u64::MAX;
+        for queue in self.queues.values_mut() {
+            queue.retain(|waiter| match waiter.deadline {
+                Some(deadline) if deadline <= now => {
+                    expired.push(waiter.tid);
+                    false
+                }
+                Some(deadline) => {
+                    next = next.min(deadline);
+                    true
+                }
+                None => true,
+            });
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+        NEXT_DEADLINE.store(next, Ordering::SeqCst);
+        expired
+    }
+
+    /// Drops `tid` from every queue, as when it is killed while waiting
+    pub fn release_thread(&mut self, tid: Tid) {
+        for queue in self.queues.values_mut() {
+            queue.retain(|waiter| waiter.tid != tid);
+        }
+        self.queues.retain(|_, queue| !queue.is_empty());
+    }
+}
+
+fn check_address(addr: usize) -> Result<(), FutexError> {
+    if addr == 0 || addr % 4 != 0 {
+        return Err(FutexError::BadAddress);
+    }
+    Ok(())
+}
+
+/// Whether some wait may have timed out by tick `now`
+pub fn deadline_passed(now: u64) -> bool {
+    now >= NEXT_DEADLINE.load(Ordering::SeqCst)
+}
+
+/// Futex wait queues of every job
+pub static FUTEXES: SpinMutex<FutexTable> = SpinMutex::new(FutexTable::new());
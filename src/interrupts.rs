@@ -3,6 +3,8 @@ use riscv::register::{
 	mcause::{self, Trap, Exception, Interrupt},
 	mtval,
 };
+use core::arch::global_asm;
+use crate::percpu;
 use crate::println;
 
 #[repr(C)]
@@ -12,12 +14,100 @@ pub struct TrapFrame {
 	pub pc: usize,          // program counter
 }
 
-#[no_mangle]
-#[link_section = ".text.trap_vector"]
+// Saves the interrupted registers in a `TrapFrame` on the current stack -
+// the kernel stack of the thread being stepped, if there is one - then runs
+// `trap_handler` and resumes at the frame's `pc`. The kernel leaves the FPU
+// off, so `fregs` isn't saved.
+global_asm!(
+	r#"
+	.section .text.trap_vector
+	.globl trap_entry
+	.align 2
+trap_entry:
+	addi sp, sp, -528
+	sd x1, 8(sp)
+	sd x3, 24(sp)
+	sd x4, 32(sp)
+	sd x5, 40(sp)
+	sd x6, 48(sp)
+	sd x7, 56(sp)
+	sd x8, 64(sp)
+	sd x9, 72(sp)
+	sd x10, 80(sp)
+	sd x11, 88(sp)
+	sd x12, 96(sp)
+	sd x13, 104(sp)
+	sd x14, 112(sp)
+	sd x15, 120(sp)
+	sd x16, 128(sp)
+	sd x17, 136(sp)
+	sd x18, 144(sp)
+	sd x19, 152(sp)
+	sd x20, 160(sp)
+	sd x21, 168(sp)
+	sd x22, 176(sp)
+	sd x23, 184(sp)
+	sd x24, 192(sp)
+	sd x25, 200(sp)
+	sd x26, 208(sp)
+	sd x27, 216(sp)
+	sd x28, 224(sp)
+	sd x29, 232(sp)
+	sd x30, 240(sp)
+	sd x31, 248(sp)
+	addi t0, sp, 528
+	sd t0, 16(sp)
+	csrr t0, mepc
+	sd t0, 512(sp)
+	mv a0, sp
+	call {handler}
+	ld t0, 512(sp)
+	csrw mepc, t0
+	ld x1, 8(sp)
+	ld x3, 24(sp)
+	ld x4, 32(sp)
+	ld x5, 40(sp)
+	ld x6, 48(sp)
+	ld x7, 56(sp)
+	ld x8, 64(sp)
+	ld x9, 72(sp)
+	ld x10, 80(sp)
+	ld x11, 88(sp)
+	ld x12, 96(sp)
+	ld x13, 104(sp)
+	ld x14, 112(sp)
+	ld x15, 120(sp)
+	ld x16, 128(sp)
+	ld x17, 136(sp)
+	ld x18, 144(sp)
+	ld x19, 152(sp)
+	ld x20, 160(sp)
+	ld x21, 168(sp)
+	ld x22, 176(sp)
+	ld x23, 184(sp)
+	ld x24, 192(sp)
+	ld x25, 200(sp)
+	ld x26, 208(sp)
+	ld x27, 216(sp)
+	ld x28, 224(sp)
+	ld x29, 232(sp)
+	ld x30, 240(sp)
+	ld x31, 248(sp)
+	addi sp, sp, 528
+	mret
+"#,
+	handler = sym trap_handler,
+);
+
+extern "C" {
+	fn trap_entry();
+}
+
 pub extern "C" fn trap_handler(trap_frame: &mut TrapFrame) {
 	let cause = mcause::read();
 	let epc = trap_frame.pc;
 
+	percpu::enter_trap();
 	match cause.cause() {
 			Trap::Exception(exception) => {
 					handle_exception(exception, epc, trap_frame);
@@ -26,6 +116,7 @@ pub extern "C" fn trap_handler(trap_frame: &mut TrapFrame) {
 					handle_interrupt(interrupt);
 			}
 	}
+	percpu::exit_trap();
 }
 
 fn handle_exception(exception: Exception, epc: usize, trap_frame: &mut TrapFrame) {
@@ -64,18 +155,12 @@ fn handle_exception(exception: Exception, epc: usize, trap_frame: &mut TrapFrame
 fn handle_interrupt(interrupt: Interrupt) {
 	match interrupt {
 			Interrupt::MachineTimer => {
-					// Clear the timer interrupt
-					unsafe {
-							mie::clear_mtimer();
-					}
-					println!("Timer interrupt");
+					// Count the tick and arm the next timer interrupt
+					crate::timer::handle_timer_interrupt();
 			}
 			Interrupt::MachineSoft => {
-					// Clear software interrupt
-					unsafe {
-							mie::clear_msoft();
-					}
-					println!("Software interrupt");
+					// Another hart sent an IPI
+					crate::ipi::handle_ipi();
 			}
 			Interrupt::MachineExternal => {
 					println!("External interrupt");
@@ -89,7 +174,7 @@ fn handle_interrupt(interrupt: Interrupt) {
 pub fn init() {
 	unsafe {
 			// Set up trap vector
-			mtvec::write(trap_handler as usize, mtvec::TrapMode::Direct);
+			mtvec::write(trap_entry as *const () as usize, mtvec::TrapMode::Direct);
 
 			// Enable machine-mode interrupts
 			mstatus::set_mie();
@@ -0,0 +1,123 @@
+use alloc::boxed::Box;
+use core::ptr::write_volatile;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use crate::irq_lock::SpinMutex;
+use crate::percpu;
+
+// QEMU virt machine CLINT software interrupt pending registers, one word per hart
+const CLINT_MSIP: usize = 0x0200_0000;
+
+/// Why one hart interrupted another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiReason {
+    /// New work is on the hart's run queue
+    Reschedule,
+    /// Page tables changed, so the hart must flush its TLB
+    TlbShootdown,
+    /// Another hart queued a function for this hart to run
+    Call,
+}
+
+impl IpiReason {
+    const ALL: [IpiReason; 3] = [IpiReason::Reschedule, IpiReason::TlbShootdown, IpiReason::Call];
+
+    fn bit(self) -> u32 {
+        1 << self as u32
+    }
+}
+
+pub(crate) type RemoteCall = Box<dyn FnOnce() + Send>;
+
+/// Only one TLB shootdown runs at a time. Deliberately not an `IrqSafeMutex`:
+/// a hart waiting here must still answer the holder's IPI.
+static SHOOTDOWN: SpinMutex<()> = SpinMutex::named("tlb_shootdown", ());
+/// Harts yet to flush their TLB for the shootdown in progress
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+fn set_msip(hart_id: usize, pending: bool) {
+    let msip = (CLINT_MSIP + 4 * hart_id) as *mut u32;
+    unsafe { write_volatile(msip, pending as u32) };
+}
+
+/// Interrupts `hart_id` for `reason`. Returns false if the hart is offline.
+pub fn send(hart_id: usize, reason: IpiReason) -> bool {
+    let cpu = percpu::cpu(hart_id);
+    if !cpu.online.load(Ordering::SeqCst) {
+        return false;
+    }
+    // The reason must be visible before the interrupt is
+    cpu.ipi_pending.fetch_or(reason.bit(), Ordering::SeqCst);
+    set_msip(hart_id, true);
+    true
+}
+
+/// Interrupts every other online hart, returning how many were sent
+pub fn broadcast(reason: IpiReason) -> usize {
+    let this = percpu::this().hart_id;
+    percpu::all()
+        .filter(|cpu| cpu.hart_id != this)
+        .filter(|cpu| send(cpu.hart_id, reason))
+        .count()
+}
+
+/// Flushes the TLB on every online hart, returning once they all have
+pub fn tlb_shootdown() {
+    let _shootdown = SHOOTDOWN.lock();
+    let this = percpu::this().hart_id;
+    for cpu in percpu::all().filter(|cpu| cpu.hart_id != this) {
+        // Counted before sending, so a fast hart can't acknowledge first
+        SHOOTDOWN_PENDING.fetch_add(1, Ordering::SeqCst);
+        if !send(cpu.hart_id, IpiReason::TlbShootdown) {
+            SHOOTDOWN_PENDING.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    unsafe { riscv::asm::sfence_vma_all() };
+    while SHOOTDOWN_PENDING.load(Ordering::SeqCst) > 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Runs `f` on `hart_id` from its IPI handler, or straight away if that is
+/// the calling hart. Returns false if the hart is offline.
+pub fn call_on(hart_id: usize, f: impl FnOnce() + Send + 'static) -> bool {
+    if hart_id == percpu::this().hart_id {
+        f();
+        return true;
+    }
+    let cpu = percpu::cpu(hart_id);
+    if !cpu.online.load(Ordering::SeqCst) {
+        return false;
+    }
+    cpu.remote_calls.lock().push_back(Box::new(f));
+    send(hart_id, IpiReason::Call)
+}
+
+/// Called from the trap handler on a machine software interrupt
+pub fn handle_ipi() {
+    let cpu = percpu::this();
+    // Clear the interrupt before taking the reasons, so a later IPI raises a new one
+    set_msip(cpu.hart_id, false);
+    let pending = cpu.ipi_pending.swap(0, Ordering::SeqCst);
+
+    for reason in IpiReason::ALL.into_iter().filter(|reason| pending & reason.bit() != 0) {
+        match reason {
+            // Waking from wfi is all it takes; the idle loop checks the run queue next
+            IpiReason::Reschedule => {}
+            IpiReason::TlbShootdown => {
+                unsafe { riscv::asm::sfence_vma_all() };
+                SHOOTDOWN_PENDING.fetch_sub(1, Ordering::SeqCst);
+            }
+            IpiReason::Call => {
+                // Don't hold the queue locked while a call runs
+                loop {
+                    let call = cpu.remote_calls.lock().pop_front();
+                    match call {
+                        Some(call) => call(),
+                        None => break,
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,184 @@
+use core::mem::ManuallyDrop;
+use core::ops::{Deref, DerefMut};
+use riscv::register::mstatus;
+use spin::{Mutex, MutexGuard};
+
+/// A spinlock that disables machine interrupts on the calling hart while it
+/// is held, so a trap handler can never spin on a lock its own hart holds.
+/// Use it for any lock that is also taken from trap context.
+pub struct IrqSafeMutex<T: ?Sized> {
+    name: Option<&'static str>,
+    inner: Mutex<T>,
+}
+
+pub struct IrqSafeMutexGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    /// Whether interrupts were enabled before the lock was taken
+    interrupts_enabled: bool,
+    #[cfg(feature = "lockdep")]
+    class: &'static str,
+}
+
+/// A plain spinlock for state never touched from trap context. Unlike
+/// `spin::Mutex` it is checked by lockdep when that feature is enabled.
+pub struct SpinMutex<T: ?Sized> {
+    name: Option<&'static str>,
+    inner: Mutex<T>,
+}
+
+pub struct SpinMutexGuard<'a, T: ?Sized> {
+    guard: ManuallyDrop<MutexGuard<'a, T>>,
+    #[cfg(feature = "lockdep")]
+    class: &'static str,
+}
+
+/// Disables machine interrupts on the calling hart, returning whether they were enabled
+fn disable_interrupts() -> bool {
+    let enabled = mstatus::read().mie();
+    unsafe { mstatus::clear_mie() };
+    enabled
+}
+
+fn restore_interrupts(enabled: bool) {
+    if enabled {
+        unsafe { mstatus::set_mie() };
+    }
+}
+
+impl<T> IrqSafeMutex<T> {
+    pub const fn new(value: T) -> Self {
+        IrqSafeMutex {
+            name: None,
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// A lock whose lockdep class is `name` rather than the type it protects
+    pub const fn named(name: &'static str, value: T) -> Self {
+        IrqSafeMutex {
+            name: Some(name),
+            inner: Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> IrqSafeMutex<T> {
+    /// Lock class lockdep tracks this lock under
+    pub fn class(&self) -> &'static str {
+        self.name.unwrap_or_else(core::any::type_name::<T>)
+    }
+
+    pub fn lock(&self) -> IrqSafeMutexGuard<'_, T> {
+        // Interrupts go off before spinning, or a trap could arrive between
+        // taking the lock and disabling them
+        let interrupts_enabled = disable_interrupts();
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquire(self.class());
+        IrqSafeMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            interrupts_enabled,
+            #[cfg(feature = "lockdep")]
+            class: self.class(),
+        }
+    }
+
+    pub fn try_lock(&self) -> Option<IrqSafeMutexGuard<'_, T>> {
+        let interrupts_enabled = disable_interrupts();
+        match self.inner.try_lock() {
+            Some(guard) => {
+                #[cfg(feature = "lockdep")]
+                crate::lockdep::acquire(self.class());
+                Some(IrqSafeMutexGuard {
+                    guard: ManuallyDrop::new(guard),
+                    interrupts_enabled,
+                    #[cfg(feature = "lockdep")]
+                    class: self.class(),
+                })
+            }
+            None => {
+                restore_interrupts(interrupts_enabled);
+                None
+            }
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for IrqSafeMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for IrqSafeMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for IrqSafeMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        // Release the lock before interrupts can come back on
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::release(self.class);
+        restore_interrupts(self.interrupts_enabled);
+    }
+}
+
+impl<T> SpinMutex<T> {
+    pub const fn new(value: T) -> Self {
+        SpinMutex {
+            name: None,
+            inner: Mutex::new(value),
+        }
+    }
+
+    /// A lock whose lockdep class is `name` rather than the type it protects
+    pub const fn named(name: &'static str, value: T) -> Self {
+        SpinMutex {
+            name: Some(name),
+            inner: Mutex::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> SpinMutex<T> {
+    /// Lock class lockdep tracks this lock under
+    pub fn class(&self) -> &'static str {
+        self.name.unwrap_or_else(core::any::type_name::<T>)
+    }
+
+    pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::acquire(self.class());
+        SpinMutexGuard {
+            guard: ManuallyDrop::new(self.inner.lock()),
+            #[cfg(feature = "lockdep")]
+            class: self.class(),
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for SpinMutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.guard
+    }
+}
+
+impl<T: ?Sized> DerefMut for SpinMutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.guard
+    }
+}
+
+impl<T: ?Sized> Drop for SpinMutexGuard<'_, T> {
+    fn drop(&mut self) {
+        unsafe { ManuallyDrop::drop(&mut self.guard) };
+        #[cfg(feature = "lockdep")]
+        crate::lockdep::release(self.class);
+    }
+}
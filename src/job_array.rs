@@ -0,0 +1,115 @@
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use crate::task::{JobId, Task};
+use crate::println;
+
+/// Replaced by the child's index in each argument of an array's template task
+pub const ARRAY_INDEX_PLACEHOLDER: &str = "%a";
+
+/// Most children one array may have, as each is a job record on the heap
+pub const MAX_ARRAY_SIZE: usize = 256;
+
+/// Identifier shared by all jobs submitted together as one array
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ArrayId(pub u64);
+
+impl fmt::Display for ArrayId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Position of a job within its array
+#[derive(Debug, Clone, Copy)]
+pub struct ArrayTask {
+    pub array: ArrayId,
+    pub index: u32,
+}
+
+pub fn substitute_index(arguments: &[String], index: u32) -> Vec<String> {
+    let index = alloc::format!("{}", index);
+    arguments
+        .iter()
+        .map(|arg| arg.replace(ARRAY_INDEX_PLACEHOLDER, &index))
+        .collect()
+}
+
+pub struct JobArray {
+    pub id: ArrayId,
+    pub jobs: Vec<JobId>,
+    /// Most children allowed to be queued or running at once (None = no limit)
+    pub max_concurrent: Option<usize>,
+    /// Children held back by the concurrency limit, in index order
+    throttled: VecDeque<(JobId, Task)>,
+}
+
+impl JobArray {
+    pub fn new(id: ArrayId, max_concurrent: Option<usize>) -> Self {
+        JobArray {
+            id,
+            jobs: Vec::new(),
+            max_concurrent,
+            throttled: VecDeque::new(),
+        }
+    }
+
+    pub fn add_throttled(&mut self, id: JobId, task: Task) {
+        self.jobs.push(id);
+        self.throttled.push_back((id, task));
+    }
+
+    pub fn is_throttled(&self, id: JobId) -> bool {
+        self.throttled.iter().any(|(throttled_id, _)| *throttled_id == id)
+    }
+
+    /// Children that have been let through the throttle
+    pub fn released(&self) -> impl Iterator<Item = &JobId> {
+        self.jobs.iter().filter(move |id| !self.is_throttled(**id))
+    }
+
+    /// Releases the next child if fewer than `max_concurrent` are active
+    pub fn release_next(&mut self, active: usize) -> Option<(JobId, Task)> {
+        if self.max_concurrent.is_some_and(|max| active >= max) {
+            return None;
+        }
+        self.throttled.pop_front()
+    }
+
+    pub fn remove_throttled(&mut self, id: JobId) -> Option<Task> {
+        let idx = self.throttled.iter().position(|(throttled_id, _)| *throttled_id == id)?;
+        self.throttled.remove(idx).map(|(_, task)| task)
+    }
+}
+
+/// How many of an array's children are in each state
+#[derive(Debug, Clone, Default)]
+pub struct ArrayStatus {
+    pub total: usize,
+    pub pending: usize,
+    pub blocked: usize,
+    pub running: usize,
+    pub completed: usize,
+    /// Failed or timed out
+    pub failed: usize,
+    pub cancelled: usize,
+}
+
+impl ArrayStatus {
+    pub fn is_finished(&self) -> bool {
+        self.completed + self.failed + self.cancelled == self.total
+    }
+
+    pub fn print(&self, id: ArrayId) {
+        println!("  - Array {}: {} jobs ({} queued, {} blocked, {} running, {} done, {} failed, {} cancelled)",
+            id,
+            self.total,
+            self.pending,
+            self.blocked,
+            self.running,
+            self.completed,
+            self.failed,
+            self.cancelled);
+    }
+}
@@ -0,0 +1,371 @@
+//! Parser for job description files.
+//!
+//! A job file is a list of `[job NAME]` sections with `key = value` lines:
+//!
+//! ```text
+//! # Lines starting with '#' are comments
+//! [job preprocess]
+//! executable = compute
+//! arguments = 10
+//! account = physics
+//! priority = 2
+//! cpu = 1
+//! memory = 256
+//! resource.licence = 1
+//!
+//! [job report]
+//! executable = echo
+//! arguments = "pipeline complete"
+//! after_ok = preprocess
+//! ```
+//!
+//! Dependencies name jobs defined earlier in the same file. Jobs without an
+//! `account` are charged to the `default` account, and a job naming a
+//! `reservation` only runs inside that advance reservation. `affinity`
+//! lists the harts a job may run on (`affinity = 0, 2`); by default any.
+//! Keys starting `max.` (`max.cpu`, `max.memory`, `max.resource.NAME`)
+//! declare the most a job will hold once it requests more while running.
+
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::Range;
+use crate::account::DEFAULT_ACCOUNT;
+use crate::batch_system::{BatchSystem, SubmitError};
+use crate::smp::MAX_HARTS;
+use crate::task::{CpuSet, Dependency, DependencyKind, JobId, ResourceRequirements, RetryPolicy, Task, TaskLimits, TaskStatus};
+use crate::println;
+
+const DEFAULT_PRIORITY: u32 = 1;
+const DEFAULT_CPU: u32 = 1;
+const DEFAULT_MEMORY: u64 = 64;
+
+#[derive(Debug, Clone)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+fn error(line: usize, message: String) -> ParseError {
+    ParseError { line, message }
+}
+
+/// A job as described in the file, before it is submitted
+#[derive(Debug, Clone)]
+pub struct JobSpec {
+    pub name: String,
+    pub line: usize,
+    pub task: Task,
+    /// Dependencies by job name, resolved to IDs on submission
+    pub dependencies: Vec<(String, DependencyKind)>,
+    /// Index range and concurrency limit when the job is an array
+    pub array: Option<(Range<u32>, Option<usize>)>,
+}
+
+impl JobSpec {
+    fn new(name: String, line: usize) -> Self {
+        JobSpec {
+            name,
+            line,
+            task: Task {
+                executable: String::new(),
+                arguments: Vec::new(),
+                account: String::from(DEFAULT_ACCOUNT),
+                priority: DEFAULT_PRIORITY,
+                resource_requirements: ResourceRequirements::new(DEFAULT_CPU, DEFAULT_MEMORY),
+                max_resources: None,
+                estimated_runtime: 0,
+                dependencies: Vec::new(),
+                limits: TaskLimits::default(),
+                retry: RetryPolicy::default(),
+                reservation: None,
+                affinity: CpuSet::all(),
+                status: TaskStatus::Pending,
+            },
+            dependencies: Vec::new(),
+            array: None,
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<JobSpec>, ParseError> {
+    let mut specs: Vec<JobSpec> = Vec::new();
+
+    for (idx, raw_line) in source.lines().enumerate() {
+        let line = idx + 1;
+        let text = raw_line.trim();
+        if text.is_empty() || text.starts_with('#') {
+            continue;
+        }
+
+        if let Some(header) = text.strip_prefix('[') {
+            let name = header
+                .strip_suffix(']')
+                .and_then(|inner| inner.trim().strip_prefix("job "))
+                .map(str::trim)
+                .filter(|name| !name.is_empty() && !name.contains(char::is_whitespace))
+                .ok_or_else(|| error(line, format!("invalid section header `{}`, expected `[job NAME]`", text)))?;
+            if specs.iter().any(|spec| spec.name == name) {
+                return Err(error(line, format!("duplicate job name `{}`", name)));
+            }
+            if let Some(previous) = specs.last() {
+                validate(previous)?;
+            }
+            specs.push(JobSpec::new(name.to_string(), line));
+            continue;
+        }
+
+        let (key, value) = text
+            .split_once('=')
+            .map(|(key, value)| (key.trim(), value.trim()))
+            .ok_or_else(|| error(line, format!("expected `key = value`, found `{}`", text)))?;
+        let (earlier, current) = match specs.split_last_mut() {
+            Some((current, earlier)) => (earlier, current),
+            None => return Err(error(line, format!("`{}` set outside of a [job] section", key))),
+        };
+        apply(current, earlier, key, value, line)?;
+    }
+
+    if let Some(last) = specs.last() {
+        validate(last)?;
+    }
+    Ok(specs)
+}
+
+fn validate(spec: &JobSpec) -> Result<(), ParseError> {
+    if spec.task.executable.is_empty() {
+        return Err(error(spec.line, format!("job `{}` is missing `executable`", spec.name)));
+    }
+    Ok(())
+}
+
+fn apply(spec: &mut JobSpec, earlier: &[JobSpec], key: &str, value: &str, line: usize) -> Result<(), ParseError> {
+    let task = &mut spec.task;
+    match key {
+        "executable" => task.executable = value.to_string(),
+        "arguments" => task.arguments = split_arguments(value, line)?,
+        "account" => task.account = value.to_string(),
+        "reservation" => task.reservation = Some(value.to_string()),
+        "affinity" => {
+            let mut affinity = CpuSet(0);
+            for hart in value.split(|c: char| c == ',' || c.is_whitespace()).filter(|hart| !hart.is_empty()) {
+                let hart = number(key, hart, line)?;
+                if hart >= MAX_HARTS {
+                    return Err(error(line, format!("`affinity` names hart {}, but there are only {}", hart, MAX_HARTS)));
+                }
+                affinity = affinity.with(hart);
+            }
+            task.affinity = affinity;
+        }
+        "priority" => task.priority = number(key, value, line)?,
+        "cpu" => task.resource_requirements.cpu = number(key, value, line)?,
+        "memory" => task.resource_requirements.memory = number(key, value, line)?,
+        "runtime" => task.estimated_runtime = number(key, value, line)?,
+        "wall_time" => task.limits.wall_time = Some(number(key, value, line)?),
+        "cpu_time" => task.limits.cpu_time = Some(number(key, value, line)?),
+        "after_ok" | "after_any" | "after_fail" => {
+            let kind = match key {
+                "after_ok" => DependencyKind::AfterOk,
+                "after_any" => DependencyKind::AfterAny,
+                _ => DependencyKind::AfterFail,
+            };
+            for name in value.split(|c: char| c == ',' || c.is_whitespace()).filter(|name| !name.is_empty()) {
+                match earlier.iter().find(|job| job.name == name) {
+                    None => return Err(error(line, format!("`{}` names unknown job `{}`", key, name))),
+                    Some(job) if job.array.is_some() => {
+                        return Err(error(line, format!("`{}` cannot depend on array job `{}`", key, name)))
+                    }
+                    Some(_) => spec.dependencies.push((name.to_string(), kind)),
+                }
+            }
+        }
+        "array" => {
+            let range = value
+                .split_once('-')
+                .and_then(|(start, end)| {
+                    let start = start.trim().parse::<u32>().ok()?;
+                    let end = end.trim().parse::<u32>().ok()?.checked_add(1)?;
+                    Some(start..end)
+                })
+                .filter(|range| !range.is_empty())
+                .ok_or_else(|| error(line, format!("invalid array range `{}`, expected `START-END`", value)))?;
+            let limit = spec.array.as_ref().and_then(|(_, limit)| *limit);
+            spec.array = Some((range, limit));
+        }
+        "array_limit" => {
+            let limit = number(key, value, line)?;
+            if limit == 0 {
+                return Err(error(line, String::from("`array_limit` must be at least 1")));
+            }
+            match spec.array.as_mut() {
+                Some((_, max_concurrent)) => *max_concurrent = Some(limit),
+                None => return Err(error(line, String::from("`array_limit` must follow `array`"))),
+            }
+        }
+        _ if key.starts_with("max.") => {
+            let max = task.max_resources.get_or_insert_with(|| ResourceRequirements::new(0, 0));
+            match &key["max.".len()..] {
+                "cpu" => max.cpu = number(key, value, line)?,
+                "memory" => max.memory = number(key, value, line)?,
+                resource => match resource.strip_prefix("resource.") {
+                    Some(name) if !name.is_empty() => max.set_generic(name, number(key, value, line)?),
+                    _ => return Err(error(line, format!("unknown key `{}`", key))),
+                },
+            }
+        }
+        _ => match key.strip_prefix("resource.") {
+            Some(name) if !name.is_empty() => {
+                task.resource_requirements.set_generic(name, number(key, value, line)?);
+            }
+            _ => return Err(error(line, format!("unknown key `{}`", key))),
+        },
+    }
+    Ok(())
+}
+
+fn number<T: core::str::FromStr>(key: &str, value: &str, line: usize) -> Result<T, ParseError> {
+    value
+        .parse()
+        .map_err(|_| error(line, format!("invalid number `{}` for `{}`", value, key)))
+}
+
+/// Splits on whitespace, keeping double-quoted arguments together
+fn split_arguments(value: &str, line: usize) -> Result<Vec<String>, ParseError> {
+    let mut arguments = Vec::new();
+    let mut rest = value.trim_start();
+
+    while !rest.is_empty() {
+        if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted
+                .find('"')
+                .ok_or_else(|| error(line, String::from("unterminated quoted argument")))?;
+            arguments.push(quoted[..end].to_string());
+            rest = quoted[end + 1..].trim_start();
+        } else {
+            let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+            arguments.push(rest[..end].to_string());
+            rest = rest[end..].trim_start();
+        }
+    }
+    Ok(arguments)
+}
+
+fn rejected(spec: &JobSpec, err: SubmitError) -> ParseError {
+    error(spec.line, format!("job `{}` rejected: {}", spec.name, err))
+}
+
+/// Parses a job file and submits every job in it, returning how many jobs
+/// and arrays were submitted. Stops at the first job the batch system rejects.
+pub fn load(batch_system: &BatchSystem, source: &str) -> Result<usize, ParseError> {
+    let specs = parse(source)?;
+    let mut submitted: Vec<(String, JobId)> = Vec::new();
+
+    for spec in &specs {
+        let mut task = spec.task.clone();
+        for (name, kind) in &spec.dependencies {
+            // The parser only accepts dependencies on earlier, non-array jobs
+            let (_, job) = submitted.iter().find(|(submitted_name, _)| submitted_name == name).unwrap();
+            task.dependencies.push(Dependency { job: *job, kind: *kind });
+        }
+
+        match &spec.array {
+            Some((range, max_concurrent)) => {
+                let array = batch_system
+                    .submit_array(task, range.clone(), *max_concurrent)
+                    .map_err(|err| rejected(spec, err))?;
+                println!("[JOBS] {} -> array {}", spec.name, array);
+            }
+            None => {
+                let id = batch_system.submit_task(task).map_err(|err| rejected(spec, err))?;
+                println!("[JOBS] {} -> job {}", spec.name, id);
+                submitted.push((spec.name.clone(), id));
+            }
+        }
+    }
+    Ok(specs.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIPELINE: &str = "
+# two jobs
+[job preprocess]
+executable = compute
+arguments = 10
+cpu = 2
+memory = 256
+resource.licence = 1
+
+[job report]
+executable = echo
+arguments = \"pipeline complete\" now
+after_ok = preprocess
+";
+
+    #[test_case]
+    fn parses_valid_file() {
+        let specs = parse(PIPELINE).unwrap();
+        assert_eq!(specs.len(), 2);
+        assert_eq!(specs[0].name, "preprocess");
+        assert_eq!(specs[0].task.executable, "compute");
+        assert_eq!(specs[0].task.account, DEFAULT_ACCOUNT);
+        assert_eq!(specs[0].task.resource_requirements.cpu, 2);
+        assert_eq!(specs[0].task.resource_requirements.memory, 256);
+        assert_eq!(specs[1].line, 10);
+        assert_eq!(specs[1].dependencies.len(), 1);
+        assert_eq!(specs[1].dependencies[0].0, "preprocess");
+    }
+
+    #[test_case]
+    fn keeps_quoted_arguments_together() {
+        let specs = parse(PIPELINE).unwrap();
+        assert_eq!(specs[1].task.arguments, ["pipeline complete", "now"]);
+    }
+
+    #[test_case]
+    fn rejects_unterminated_quote() {
+        let err = parse("[job a]\nexecutable = echo\narguments = \"open").unwrap_err();
+        assert_eq!(err.line, 3);
+    }
+
+    #[test_case]
+    fn rejects_unknown_keys() {
+        assert_eq!(parse("[job a]\nexecutable = echo\ncolour = red").unwrap_err().line, 3);
+        assert_eq!(parse("[job a]\nexecutable = echo\nmax.colour = 1").unwrap_err().line, 3);
+        assert_eq!(parse("executable = echo").unwrap_err().line, 1);
+    }
+
+    #[test_case]
+    fn rejects_job_without_executable() {
+        assert_eq!(parse("[job a]\ncpu = 1\n[job b]\nexecutable = echo").unwrap_err().line, 1);
+    }
+
+    #[test_case]
+    fn parses_array_range() {
+        let specs = parse("[job a]\nexecutable = echo\narray = 1-4\narray_limit = 2").unwrap();
+        assert_eq!(specs[0].array, Some((1..5, Some(2))));
+    }
+
+    #[test_case]
+    fn rejects_bad_array_ranges() {
+        for range in ["4-1", "1", "a-3", "1-", "0-4294967295"] {
+            let source = format!("[job a]\nexecutable = echo\narray = {}", range);
+            assert_eq!(parse(&source).unwrap_err().line, 3);
+        }
+    }
+
+    #[test_case]
+    fn rejects_zero_array_limit() {
+        let err = parse("[job a]\nexecutable = echo\narray = 1-4\narray_limit = 0").unwrap_err();
+        assert_eq!(err.line, 4);
+    }
+}
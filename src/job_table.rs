@@ -0,0 +1,222 @@
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec::Vec;
+use crate::executor::JobOutput;
+use crate::job_array::ArrayTask;
+use crate::task::{JobId, Task, TaskStatus};
+use crate::watchdog::Overrun;
+use crate::println;
+
+/// One run of a job; jobs with a retry policy may have several
+#[derive(Debug, Clone)]
+pub struct Attempt {
+    pub start_tick: u64,
+    pub end_tick: u64,
+    pub status: TaskStatus,
+    pub exit_code: Option<i32>,
+    pub failure_reason: Option<String>,
+}
+
+/// Everything the batch system knows about a job, kept after it finishes
+#[derive(Debug, Clone)]
+pub struct JobRecord {
+    pub id: JobId,
+    pub task: Task,
+    pub status: TaskStatus,
+    pub submit_tick: u64,
+    pub start_tick: Option<u64>,
+    pub end_tick: Option<u64>,
+    pub exit_code: Option<i32>,
+    pub failure_reason: Option<String>,
+    /// CPU time summed over all attempts
+    pub cpu_ticks: u64,
+    /// Largest data segment of any attempt, in KB
+    pub peak_memory: u64,
+    /// The time limit the job was killed for exceeding
+    pub overrun: Option<Overrun>,
+    pub attempts: Vec<Attempt>,
+    /// Output captured from the most recent attempt
+    pub output: JobOutput,
+    pub array_task: Option<ArrayTask>,
+}
+
+#[derive(Default)]
+pub struct JobTable {
+    jobs: BTreeMap<JobId, JobRecord>,
+}
+
+impl JobTable {
+    pub const fn new() -> Self {
+        JobTable {
+            jobs: BTreeMap::new(),
+        }
+    }
+
+    pub fn insert(&mut self, id: JobId, task: Task, status: TaskStatus, submit_tick: u64) {
+        self.jobs.insert(id, JobRecord {
+            id,
+            task,
+            status,
+            submit_tick,
+            start_tick: None,
+            end_tick: None,
+            exit_code: None,
+            failure_reason: None,
+            cpu_ticks: 0,
+            peak_memory: 0,
+            overrun: None,
+            attempts: Vec::new(),
+            output: JobOutput::default(),
+            array_task: None,
+        });
+    }
+
+    pub fn get(&self, id: JobId) -> Option<&JobRecord> {
+        self.jobs.get(&id)
+    }
+
+    pub fn get_status(&self, id: JobId) -> Option<&TaskStatus> {
+        self.jobs.get(&id).map(|job| &job.status)
+    }
+
+    pub fn set_status(&mut self, id: JobId, status: TaskStatus) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.status = status;
+        }
+    }
+
+    pub fn mark_started(&mut self, id: JobId, tick: u64) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.status = TaskStatus::Running;
+            job.start_tick = Some(tick);
+        }
+    }
+
+    pub fn mark_finished(
+        &mut self,
+        id: JobId,
+        status: TaskStatus,
+        tick: u64,
+        exit_code: Option<i32>,
+        failure_reason: Option<String>,
+    ) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.status = status;
+            job.end_tick = Some(tick);
+            job.exit_code = exit_code;
+            job.failure_reason = failure_reason;
+        }
+    }
+
+    /// Adds the usage of an attempt that just ended
+    pub fn record_usage(&mut self, id: JobId, cpu_ticks: u64, peak_memory: u64, overrun: Option<Overrun>) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.cpu_ticks += cpu_ticks;
+            job.peak_memory = job.peak_memory.max(peak_memory);
+            job.overrun = overrun;
+        }
+    }
+
+    /// Records the end of the current attempt and returns how many attempts
+    /// the job has made so far
+    pub fn record_attempt(
+        &mut self,
+        id: JobId,
+        status: TaskStatus,
+        tick: u64,
+        exit_code: Option<i32>,
+        failure_reason: Option<String>,
+    ) -> u32 {
+        match self.jobs.get_mut(&id) {
+            Some(job) => {
+                job.attempts.push(Attempt {
+                    start_tick: job.start_tick.unwrap_or(tick),
+                    end_tick: tick,
+                    status,
+                    exit_code,
+                    failure_reason,
+                });
+                job.attempts.len() as u32
+            }
+            None => 0,
+        }
+    }
+
+    pub fn set_array_task(&mut self, id: JobId, array_task: ArrayTask) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.array_task = Some(array_task);
+        }
+    }
+
+    pub fn record_output(&mut self, id: JobId, output: JobOutput) {
+        if let Some(job) = self.jobs.get_mut(&id) {
+            job.output = output;
+        }
+    }
+
+    pub fn count(&self, wanted: fn(&TaskStatus) -> bool) -> usize {
+        self.jobs.values().filter(|job| wanted(&job.status)).count()
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &JobRecord> {
+        self.jobs.values()
+    }
+
+    pub fn records(&self) -> Vec<JobRecord> {
+        self.jobs.values().cloned().collect()
+    }
+
+    pub fn print(&self) {
+        println!("[BATCH] Job table:");
+        for job in self.jobs.values() {
+            match job.array_task {
+                Some(array_task) => println!("  - Job {} ({}, array {}[{}]): {:?}",
+                    job.id, job.task.executable, array_task.array, array_task.index, job.status),
+                None => println!("  - Job {} ({}): {:?}", job.id, job.task.executable, job.status),
+            }
+            println!("      submitted at tick {}, started at {}, ended at {}",
+                job.submit_tick,
+                format_tick(job.start_tick),
+                format_tick(job.end_tick));
+            if job.start_tick.is_some() {
+                println!("      cpu time: {} ticks, peak memory: {}KB", job.cpu_ticks, job.peak_memory);
+            }
+            if let Some(exit_code) = job.exit_code {
+                println!("      exit code: {}", exit_code);
+            }
+            if let Some(reason) = &job.failure_reason {
+                println!("      reason: {}", reason);
+            }
+            if !job.output.stdout.is_empty() || !job.output.stderr.is_empty() {
+                println!("      output: {} bytes stdout, {} bytes stderr",
+                    job.output.stdout.len(),
+                    job.output.stderr.len());
+            }
+            if job.attempts.len() > 1 {
+                for (number, attempt) in job.attempts.iter().enumerate() {
+                    println!("      attempt {}: {:?} (ticks {}-{}){}",
+                        number + 1,
+                        attempt.status,
+                        attempt.start_tick,
+                        attempt.end_tick,
+                        attempt.failure_reason.as_ref().map(|r| alloc::format!(": {}", r)).unwrap_or_default());
+                }
+            }
+        }
+    }
+}
+
+fn format_tick(tick: Option<u64>) -> String {
+    match tick {
+        Some(tick) => alloc::format!("tick {}", tick),
+        None => String::from("-"),
+    }
+}
@@ -0,0 +1,183 @@
+//! Kernel stacks of job threads.
+//!
+//! Every thread runs its steps on a stack of its own rather than on the
+//! stack of the hart stepping it. To step a thread the hart switches to the
+//! thread's stack, the step runs there, and the thread switches back; the
+//! registers each side must keep are saved in a frame at the top of the
+//! thread's stack. A trap taken during a step saves its `TrapFrame` on the
+//! same stack, so each thread also has its own trap context.
+//!
+//! Stacks come from a fixed pool, as the heap never frees memory.
+
+use core::arch::global_asm;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::ops::Range;
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+use crate::executor::JobContext;
+
+/// Bytes in each thread's kernel stack
+pub const KERNEL_STACK_SIZE: usize = 16 * 1024;
+
+/// Most threads, across all jobs, that can exist at once
+pub const MAX_THREADS: usize = 64;
+
+/// Registers a call must preserve, saved by `kernel_stack_switch`
+#[repr(C)]
+#[derive(Default)]
+struct SwitchContext {
+    ra: usize,
+    sp: usize,
+    s: [usize; 12],
+}
+
+/// Kept at the top of each stack, above the frames of the step it runs
+#[repr(C)]
+struct ThreadFrame {
+    /// Where the thread resumes when a hart next steps it
+    thread: SwitchContext,
+    /// Where the hart resumes once the step returns
+    hart: SwitchContext,
+    step: fn(&mut JobContext),
+    /// Context of the step in progress; only valid while it runs
+    ctx: *mut JobContext,
+}
+
+/// Space kept for the frame, keeping the stack below it 16-byte aligned
+const FRAME_SIZE: usize = (size_of::<ThreadFrame>() + 15) & !15;
+
+#[repr(C, align(16))]
+struct Stack([u8; KERNEL_STACK_SIZE]);
+
+struct StackPool {
+    stacks: UnsafeCell<[Stack; MAX_THREADS]>,
+    taken: [AtomicBool; MAX_THREADS],
+}
+
+// A stack is only touched through the `KernelStack` it was handed out as
+unsafe impl Sync for StackPool {}
+
+static POOL: StackPool = StackPool {
+    stacks: UnsafeCell::new([const { Stack([0; KERNEL_STACK_SIZE]) }; MAX_THREADS]),
+    taken: [const { AtomicBool::new(false) }; MAX_THREADS],
+};
+
+global_asm!(
+    r#"
+    .section .text
+    .globl kernel_stack_switch
+    .align 2
+# kernel_stack_switch(current: *mut SwitchContext, next: *const SwitchContext)
+kernel_stack_switch:
+    sd ra, 0(a0)
+    sd sp, 8(a0)
+    sd s0, 16(a0)
+    sd s1, 24(a0)
+    sd s2, 32(a0)
+    sd s3, 40(a0)
+    sd s4, 48(a0)
+    sd s5, 56(a0)
+    sd s6, 64(a0)
+    sd s7, 72(a0)
+    sd s8, 80(a0)
+    sd s9, 88(a0)
+    sd s10, 96(a0)
+    sd s11, 104(a0)
+    ld ra, 0(a1)
+    ld sp, 8(a1)
+    ld s0, 16(a1)
+    ld s1, 24(a1)
+    ld s2, 32(a1)
+    ld s3, 40(a1)
+    ld s4, 48(a1)
+    ld s5, 56(a1)
+    ld s6, 64(a1)
+    ld s7, 72(a1)
+    ld s8, 80(a1)
+    ld s9, 88(a1)
+    ld s10, 96(a1)
+    ld s11, 104(a1)
+    ret
+
+    .globl kernel_stack_start
+    .align 2
+# First switch to a new stack lands here, with the stack's frame in s1
+kernel_stack_start:
+    mv a0, s1
+    call {main}
+"#,
+    main = sym kernel_stack_main,
+);
+
+extern "C" {
+    fn kernel_stack_switch(current: *mut SwitchContext, next: *const SwitchContext);
+    fn kernel_stack_start();
+}
+
+/// Body of every thread: runs a step each time a hart switches to it
+extern "C" fn kernel_stack_main(frame: *mut ThreadFrame) -> ! {
+    loop {
+        // The hart waits in `run`, holding the context's borrow, until the
+        // step is done and this switches back
+        unsafe {
+            ((*frame).step)(&mut *(*frame).ctx);
+            kernel_stack_switch(ptr::addr_of_mut!((*frame).thread), ptr::addr_of!((*frame).hart));
+        }
+    }
+}
+
+/// A thread's kernel stack, returned to the pool when dropped
+pub struct KernelStack {
+    index: usize,
+}
+
+impl KernelStack {
+    /// Takes a free stack for a thread that runs `step`, or None if every
+    /// stack is in use
+    pub fn new(step: fn(&mut JobContext)) -> Option<Self> {
+        let index = POOL
+            .taken
+            .iter()
+            .position(|taken| taken.compare_exchange(false, true, Ordering::SeqCst, Ordering::SeqCst).is_ok())?;
+        let stack = KernelStack { index };
+
+        let frame = stack.frame();
+        let mut thread = SwitchContext {
+            ra: kernel_stack_start as *const () as usize,
+            sp: frame as usize,
+            ..SwitchContext::default()
+        };
+        thread.s[1] = frame as usize;
+        let hart = SwitchContext::default();
+        unsafe { frame.write(ThreadFrame { thread, hart, step, ctx: ptr::null_mut() }) };
+        Some(stack)
+    }
+
+    /// Runs one step of the thread on its stack, returning once it has
+    pub fn run(&mut self, ctx: &mut JobContext) {
+        let frame = self.frame();
+        unsafe {
+            (*frame).ctx = ctx;
+            kernel_stack_switch(ptr::addr_of_mut!((*frame).hart), ptr::addr_of!((*frame).thread));
+            (*frame).ctx = ptr::null_mut();
+        }
+    }
+
+    fn frame(&self) -> *mut ThreadFrame {
+        let stack = POOL.stacks.get().cast::<Stack>().wrapping_add(self.index);
+        stack.wrapping_add(1).cast::<u8>().wrapping_sub(FRAME_SIZE).cast()
+    }
+}
+
+impl Drop for KernelStack {
+    fn drop(&mut self) {
+        POOL.taken[self.index].store(false, Ordering::SeqCst);
+    }
+}
+
+/// Addresses of every thread's kernel stack
+pub fn pool_range() -> Range<usize> {
+    let start = POOL.stacks.get() as usize;
+    start..start + size_of::<[Stack; MAX_THREADS]>()
+}
@@ -9,14 +9,34 @@ extern crate alloc;
 
 pub mod scheduler;
 pub mod resource_manager;
+pub mod account;
+pub mod accounting;
+pub mod reservation;
 pub mod task;
 pub mod uart;
 pub mod interrupts;
 pub mod memory;
 pub mod batch_system;
+pub mod job_table;
+pub mod job_array;
+pub mod job_file;
 pub mod executor;
-
-use spin::Mutex;
+pub mod timer;
+pub mod syscall;
+pub mod programs;
+pub mod watchdog;
+pub mod irq_lock;
+pub mod sync;
+pub mod futex;
+pub mod kernel_stack;
+#[cfg(feature = "lockdep")]
+pub mod lockdep;
+pub mod smp;
+pub mod percpu;
+pub mod ipi;
+pub mod load_balancer;
+
+use irq_lock::{IrqSafeMutex, IrqSafeMutexGuard};
 use core::alloc::GlobalAlloc;
 use core::alloc::Layout;
 use core::ptr::null_mut;
@@ -42,17 +62,17 @@ macro_rules! println {
 
 // Heap allocator
 pub struct Locked<A> {
-    inner: Mutex<A>,
+    inner: IrqSafeMutex<A>,
 }
 
 impl<A> Locked<A> {
     pub const fn new(inner: A) -> Self {
         Locked {
-            inner: Mutex::new(inner),
+            inner: IrqSafeMutex::new(inner),
         }
     }
 
-    pub fn lock(&self) -> spin::MutexGuard<A> {
+    pub fn lock(&self) -> IrqSafeMutexGuard<A> {
         self.inner.lock()
     }
 }
@@ -118,6 +138,26 @@ pub unsafe fn init_heap() {
     ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
 }
 
+/// Exit status reported to QEMU through the virt machine's test device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum QemuExitCode {
+    Success = 0x5555,
+    /// QEMU exits with status 1
+    Failed = (1 << 16) | 0x3333,
+}
+
+/// Address of the virt machine's SiFive test device
+const QEMU_TEST_DEVICE: usize = 0x10_0000;
+
+/// Powers off QEMU with `code` as its exit status
+pub fn exit_qemu(code: QemuExitCode) -> ! {
+    unsafe { core::ptr::write_volatile(QEMU_TEST_DEVICE as *mut u32, code as u32) };
+    loop {
+        unsafe { riscv::asm::wfi() };
+    }
+}
+
 pub fn test_runner(tests: &[&dyn Fn()]) {
     println!("Running {} tests", tests.len());
     for test in tests {
@@ -125,3 +165,23 @@ pub fn test_runner(tests: &[&dyn Fn()]) {
     }
     println!("All tests passed!");
 }
+
+/// Entry point of `cargo test --lib`: boots just enough to run the tests,
+/// then exits QEMU with their result
+#[cfg(test)]
+#[no_mangle]
+pub extern "C" fn kernel_main() -> ! {
+    smp::init_boot_hart();
+    // Without paging the heap must sit in RAM, where memory.rs keeps room for it
+    ALLOCATOR.lock().init(memory::HEAP_START, HEAP_SIZE);
+    test_main();
+    exit_qemu(QemuExitCode::Success)
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    println!("[failed]");
+    println!("Error: {}\n", info);
+    exit_qemu(QemuExitCode::Failed)
+}
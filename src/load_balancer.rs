@@ -0,0 +1,90 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::batch_system::ActiveThread;
+use crate::ipi::{self, IpiReason};
+use crate::percpu::{self, PerCpu};
+use crate::println;
+
+/// Ticks between load balancing passes
+pub const BALANCE_INTERVAL: u64 = 10;
+
+static LAST_BALANCE: AtomicU64 = AtomicU64::new(0);
+
+fn online_harts() -> impl Iterator<Item = &'static PerCpu> {
+    percpu::all().filter(|cpu| cpu.online.load(Ordering::SeqCst))
+}
+
+/// Queues a new or woken thread on the least loaded online hart its job may
+/// run on, waking that hart if it isn't the caller. Returns the hart chosen.
+pub fn place(thread: ActiveThread) -> usize {
+    let affinity = thread.job.task.affinity;
+    let cpu = online_harts()
+        .filter(|cpu| affinity.contains(cpu.hart_id))
+        .min_by_key(|cpu| cpu.load())
+        .unwrap_or_else(|| {
+            // Every hart the job may use failed to come online
+            println!("[SMP] No online hart in job {}'s affinity, ignoring it", thread.job.id);
+            online_harts().min_by_key(|cpu| cpu.load()).unwrap_or(percpu::this())
+        });
+    push(cpu, thread);
+    cpu.hart_id
+}
+
+fn push(cpu: &PerCpu, thread: ActiveThread) {
+    cpu.run_queue.lock().push_back(thread);
+    if cpu.hart_id != percpu::this().hart_id {
+        ipi::send(cpu.hart_id, IpiReason::Reschedule);
+    }
+}
+
+/// Takes the thread at the back of `victim`'s run queue if it may run on `hart_id`
+fn take(victim: &PerCpu, hart_id: usize) -> Option<ActiveThread> {
+    let mut queue = victim.run_queue.lock();
+    let idx = queue.iter().rposition(|thread| thread.job.task.affinity.contains(hart_id))?;
+    queue.remove(idx)
+}
+
+/// Called by a hart whose run queue is empty: takes a waiting thread allowed
+/// to run here from the busiest hart
+pub fn steal() -> Option<ActiveThread> {
+    let thief = percpu::this();
+    // Idle harts call this in a loop, so it must not allocate
+    let victim = online_harts()
+        .filter(|cpu| cpu.hart_id != thief.hart_id)
+        .max_by_key(|cpu| cpu.load())?;
+    let thread = take(victim, thief.hart_id)?;
+    thief.steals.fetch_add(1, Ordering::Relaxed);
+    println!("[SMP] Hart {} stole thread {} of job {} from hart {}",
+        thief.hart_id, thread.ctx.tid, thread.job.id, victim.hart_id);
+    Some(thread)
+}
+
+/// Evens out run queues by moving waiting threads from the busiest hart to
+/// the idlest, at most once every `BALANCE_INTERVAL` ticks. Returns how many
+/// threads moved.
+pub fn balance(now: u64) -> usize {
+    let last = LAST_BALANCE.load(Ordering::SeqCst);
+    if now < last + BALANCE_INTERVAL
+        || LAST_BALANCE.compare_exchange(last, now, Ordering::SeqCst, Ordering::SeqCst).is_err()
+    {
+        return 0;
+    }
+
+    let mut moved = 0;
+    while let (Some(busiest), Some(idlest)) = (
+        online_harts().max_by_key(|cpu| cpu.load()),
+        online_harts().min_by_key(|cpu| cpu.load()),
+    ) {
+        // Moving a thread between harts one apart would only swap which is busier
+        if busiest.load() < idlest.load() + 2 {
+            break;
+        }
+        let Some(thread) = take(busiest, idlest.hart_id) else {
+            break;
+        };
+        println!("[SMP] Balancing: moved thread {} of job {} from hart {} to hart {}",
+            thread.ctx.tid, thread.job.id, busiest.hart_id, idlest.hart_id);
+        push(idlest, thread);
+        moved += 1;
+    }
+    moved
+}
@@ -0,0 +1,318 @@
+//! Lock dependency checker, built with the `lockdep` feature.
+//!
+//! Every `IrqSafeMutex` and `SpinMutex` belongs to a lock class, named by the
+//! type it protects unless it was created with `named`. Each hart keeps a
+//! stack of the classes it holds; taking class B while holding A records the
+//! edge A -> B. A new edge that closes a cycle (the classic AB/BA inversion
+//! or longer) is reported, as is a class taken both in interrupt context and
+//! with interrupts enabled, since an interrupt arriving while the lock is
+//! held would spin on it forever. Reports go straight to the UART, without
+//! its lock, and include the held locks and the call chain.
+//!
+//! Bookkeeping never allocates: the allocator's own lock is tracked.
+
+use core::fmt::Write;
+use riscv::register::mstatus;
+use spin::Mutex;
+use crate::kernel_stack;
+use crate::percpu;
+use crate::smp::{self, MAX_HARTS};
+use crate::uart::Uart;
+
+/// Most lock classes tracked; classes beyond this are ignored
+const MAX_CLASSES: usize = 64;
+/// Most locks one hart can hold at once and still be checked
+const MAX_HELD: usize = 16;
+/// Frames kept of each call chain
+const MAX_FRAMES: usize = 8;
+
+type CallChain = [usize; MAX_FRAMES];
+
+struct Graph {
+    names: [&'static str; MAX_CLASSES],
+    count: usize,
+    /// Bit B of `after[A]` is set once class B was taken while A was held
+    after: [u64; MAX_CLASSES],
+    /// Bit B of `reported[A]` is set once an A -> B inversion was reported
+    reported: [u64; MAX_CLASSES],
+    used_in_irq: u64,
+    used_with_irqs_on: u64,
+    /// Where each class was first taken in interrupt context
+    irq_chain: [CallChain; MAX_CLASSES],
+    /// Where each class was first taken with interrupts enabled
+    irqs_on_chain: [CallChain; MAX_CLASSES],
+    irq_reported: u64,
+}
+
+struct Held {
+    classes: [usize; MAX_HELD],
+    depth: usize,
+}
+
+// Internal locks are plain spin::Mutex so checking doesn't recurse, and are
+// only taken with interrupts disabled
+static GRAPH: Mutex<Graph> = Mutex::new(Graph {
+    names: [""; MAX_CLASSES],
+    count: 0,
+    after: [0; MAX_CLASSES],
+    reported: [0; MAX_CLASSES],
+    used_in_irq: 0,
+    used_with_irqs_on: 0,
+    irq_chain: [[0; MAX_FRAMES]; MAX_CLASSES],
+    irqs_on_chain: [[0; MAX_FRAMES]; MAX_CLASSES],
+    irq_reported: 0,
+});
+
+static HELD: [Mutex<Held>; MAX_HARTS] = [const {
+    Mutex::new(Held {
+        classes: [0; MAX_HELD],
+        depth: 0,
+    })
+}; MAX_HARTS];
+
+extern "C" {
+    static _stack_start: u8;
+    static _stack_end: u8;
+}
+
+impl Graph {
+    fn class(&mut self, name: &'static str) -> Option<usize> {
+        if let Some(class) = self.names[..self.count].iter().position(|&known| known == name) {
+            return Some(class);
+        }
+        if self.count == MAX_CLASSES {
+            return None;
+        }
+        self.names[self.count] = name;
+        self.count += 1;
+        Some(self.count - 1)
+    }
+
+    /// Classes on a path from `from` to `to`, written into `path` (which
+    /// starts with `from`), or None if `to` can't be reached
+    fn path(&self, from: usize, to: usize, path: &mut [usize; MAX_CLASSES]) -> Option<usize> {
+        let mut parent = [usize::MAX; MAX_CLASSES];
+        let mut visited = 1u64 << from;
+        let mut frontier = 1u64 << from;
+        while frontier != 0 && visited & (1 << to) == 0 {
+            let mut next = 0;
+            for class in (0..self.count).filter(|class| frontier & (1 << class) != 0) {
+                let new = self.after[class] & !visited & !next;
+                for child in (0..self.count).filter(|child| new & (1 << child) != 0) {
+                    parent[child] = class;
+                }
+                next |= new;
+            }
+            visited |= next;
+            frontier = next;
+        }
+        if visited & (1 << to) == 0 {
+            return None;
+        }
+
+        let mut len = 0;
+        let mut class = to;
+        while class != from {
+            path[len] = class;
+            len += 1;
+            class = parent[class];
+        }
+        path[len] = from;
+        path[..=len].reverse();
+        Some(len + 1)
+    }
+}
+
+/// Records that the calling hart is about to take a lock of class `name`
+pub fn acquire(name: &'static str) {
+    let irqs_on = mstatus::read().mie();
+    unsafe { mstatus::clear_mie() };
+    let in_irq = percpu::in_interrupt();
+
+    let mut graph = GRAPH.lock();
+    let mut held = HELD[smp::hart_id()].lock();
+    if let Some(class) = graph.class(name) {
+        check_order(&mut graph, &held, class);
+        check_irq_state(&mut graph, &held, class, in_irq, irqs_on);
+        if held.depth < MAX_HELD {
+            let depth = held.depth;
+            held.classes[depth] = class;
+        }
+        held.depth += 1;
+    }
+    drop(held);
+    drop(graph);
+
+    if irqs_on {
+        unsafe { mstatus::set_mie() };
+    }
+}
+
+/// Records that the calling hart released a lock of class `name`
+pub fn release(name: &'static str) {
+    let irqs_on = mstatus::read().mie();
+    unsafe { mstatus::clear_mie() };
+
+    let graph = GRAPH.lock();
+    let mut held = HELD[smp::hart_id()].lock();
+    if let Some(class) = graph.names[..graph.count].iter().position(|&known| known == name) {
+        // Locks needn't be released in the order they were taken
+        let depth = held.depth.min(MAX_HELD);
+        if let Some(idx) = held.classes[..depth].iter().rposition(|&c| c == class) {
+            held.classes.copy_within(idx + 1..depth, idx);
+        }
+        held.depth = held.depth.saturating_sub(1);
+    }
+    drop(held);
+    drop(graph);
+
+    if irqs_on {
+        unsafe { mstatus::set_mie() };
+    }
+}
+
+fn check_order(graph: &mut Graph, held: &Held, class: usize) {
+    for &holding in &held.classes[..held.depth.min(MAX_HELD)] {
+        if holding == class {
+            report_once(graph, held, holding, class, |out, graph| {
+                let _ = writeln!(out, "[LOCKDEP] Recursive locking: {} is already held by this hart",
+                    graph.names[class]);
+            });
+            continue;
+        }
+        if graph.after[holding] & (1 << class) != 0 {
+            continue;
+        }
+
+        // The new edge closes a cycle if `holding` was ever taken after `class`
+        let mut path = [0; MAX_CLASSES];
+        match graph.path(class, holding, &mut path) {
+            Some(len) => report_once(graph, held, holding, class, |out, graph| {
+                let _ = writeln!(out, "[LOCKDEP] Possible deadlock: taking {} while holding {}",
+                    graph.names[class], graph.names[holding]);
+                let _ = write!(out, "[LOCKDEP] but elsewhere the order was: {}", graph.names[path[0]]);
+                for &step in &path[1..len] {
+                    let _ = write!(out, " -> {}", graph.names[step]);
+                }
+                let _ = writeln!(out);
+            }),
+            None => graph.after[holding] |= 1 << class,
+        }
+    }
+}
+
+fn check_irq_state(graph: &mut Graph, held: &Held, class: usize, in_irq: bool, irqs_on: bool) {
+    let bit = 1u64 << class;
+    let other_chain = if in_irq {
+        if graph.used_in_irq & bit == 0 {
+            graph.used_in_irq |= bit;
+            graph.irq_chain[class] = call_chain();
+        }
+        (graph.used_with_irqs_on & bit != 0).then(|| graph.irqs_on_chain[class])
+    } else if irqs_on {
+        if graph.used_with_irqs_on & bit == 0 {
+            graph.used_with_irqs_on |= bit;
+            graph.irqs_on_chain[class] = call_chain();
+        }
+        (graph.used_in_irq & bit != 0).then(|| graph.irq_chain[class])
+    } else {
+        None
+    };
+
+    let Some(other_chain) = other_chain else {
+        return;
+    };
+    if graph.irq_reported & bit != 0 {
+        return;
+    }
+    graph.irq_reported |= bit;
+
+    let mut out = Uart::new();
+    let _ = writeln!(out, "[LOCKDEP] {} is taken in interrupt context and with interrupts enabled", graph.names[class]);
+    let _ = writeln!(out, "[LOCKDEP] An interrupt while it is held would deadlock; use IrqSafeMutex");
+    let _ = write!(out, "[LOCKDEP] Other use:");
+    print_chain(&mut out, &other_chain);
+    print_context(&mut out, graph, held);
+}
+
+fn report_once(graph: &mut Graph, held: &Held, holding: usize, class: usize, describe: impl FnOnce(&mut Uart, &Graph)) {
+    if graph.reported[holding] & (1 << class) != 0 {
+        return;
+    }
+    graph.reported[holding] |= 1 << class;
+
+    let mut out = Uart::new();
+    describe(&mut out, graph);
+    print_context(&mut out, graph, held);
+}
+
+/// Prints the locks the hart holds and the call chain that got here
+fn print_context(out: &mut Uart, graph: &Graph, held: &Held) {
+    let _ = write!(out, "[LOCKDEP] Hart {} holds:", smp::hart_id());
+    for &class in &held.classes[..held.depth.min(MAX_HELD)] {
+        let _ = write!(out, " {}", graph.names[class]);
+    }
+    let _ = writeln!(out);
+    let _ = write!(out, "[LOCKDEP] Call chain:");
+    print_chain(out, &call_chain());
+}
+
+fn print_chain(out: &mut Uart, chain: &CallChain) {
+    for &ra in chain.iter().take_while(|&&ra| ra != 0) {
+        let _ = write!(out, " {:#x}", ra);
+    }
+    let _ = writeln!(out);
+}
+
+/// Return addresses of the callers, found by walking frame pointers (the
+/// kernel is built with `force-frame-pointers`)
+fn call_chain() -> CallChain {
+    let mut chain = [0; MAX_FRAMES];
+    let stack_start = core::ptr::addr_of!(_stack_start) as usize;
+    let stack_end = core::ptr::addr_of!(_stack_end) as usize;
+    // Threads' steps run on their own kernel stacks
+    let thread_stacks = kernel_stack::pool_range();
+    let mut fp: usize;
+    unsafe { core::arch::asm!("mv {}, s0", out(reg) fp) };
+
+    for frame in chain.iter_mut() {
+        let on_hart_stack = fp >= stack_start + 16 && fp <= stack_end;
+        let on_thread_stack = fp >= thread_stacks.start + 16 && fp <= thread_stacks.end;
+        if fp % 8 != 0 || !(on_hart_stack || on_thread_stack) {
+            break;
+        }
+        // The return address is saved just below the frame pointer, and the
+        // caller's frame pointer below that
+        unsafe {
+            *frame = *((fp - 8) as *const usize);
+            fp = *((fp - 16) as *const usize);
+        }
+    }
+    chain
+}
+
+/// Prints every lock class seen and the order they were taken in
+pub fn print_summary() {
+    let irqs_on = mstatus::read().mie();
+    unsafe { mstatus::clear_mie() };
+
+    let graph = GRAPH.lock();
+    let mut out = Uart::new();
+    let edges: u32 = graph.after[..graph.count].iter().map(|after| after.count_ones()).sum();
+    let _ = writeln!(out, "[LOCKDEP] {} lock classes, {} dependencies:", graph.count, edges);
+    for class in 0..graph.count {
+        let _ = write!(out, "  - {}", graph.names[class]);
+        if graph.used_in_irq & (1 << class) != 0 {
+            let _ = write!(out, " (irq)");
+        }
+        for after in (0..graph.count).filter(|after| graph.after[class] & (1 << after) != 0) {
+            let _ = write!(out, "\n      -> {}", graph.names[after]);
+        }
+        let _ = writeln!(out);
+    }
+    drop(graph);
+
+    if irqs_on {
+        unsafe { mstatus::set_mie() };
+    }
+}
@@ -7,15 +7,27 @@
 
 extern crate alloc;
 
+use alloc::boxed::Box;
+
 use core::panic::PanicInfo;
 use blog_os::{println, HEAP_START, HEAP_SIZE};
+use blog_os::account::{AccountQuota, FairSharePolicy};
 use blog_os::batch_system::BatchSystem;
-use blog_os::task::{Task, ResourceRequirements, TaskStatus};
+use blog_os::scheduler::AgingPolicy;
+use blog_os::reservation::AdvanceReservation;
+use blog_os::resource_manager::AllocationPolicy;
+use blog_os::task::{TaskLimits, ResourceRequirements};
 use blog_os::ALLOCATOR;
 use riscv::register::{mhartid, marchid, mimpid, mvendorid};
 
+/// Job file embedded in the kernel image and submitted at boot
+static BOOT_JOBS: &str = include_str!("../jobs/boot.jobs");
+
 #[no_mangle]
 pub extern "C" fn kernel_main() -> ! {
+    // Per-CPU data must be reachable before the first trap
+    blog_os::smp::init_boot_hart();
+
     // Initialize early console
     blog_os::uart::init();
     println!("DEBUG: UART initialized");
@@ -39,6 +51,10 @@ pub extern "C" fn kernel_main() -> ! {
     blog_os::interrupts::init();
     println!("  [OK] Interrupts initialized");
 
+    println!("→ Initializing timer...");
+    blog_os::timer::init();
+    println!("  [OK] Timer initialized");
+
     println!("→ Initializing memory management...");
     blog_os::memory::init();
     println!("  [OK] Memory management initialized");
@@ -51,84 +67,76 @@ pub extern "C" fn kernel_main() -> ! {
 
     // Initialize batch system
     println!("Initializing Batch System:");
-    let batch_system = BatchSystem::new(ResourceRequirements {
-        cpu: 4,
-        memory: 1024,
+    // Leaked so the secondary harts' idle loops can share it
+    let batch_system: &'static BatchSystem = Box::leak(Box::new(BatchSystem::new(
+        ResourceRequirements::new(4, 1024)
+            .with("licence", 2)
+            .with("scratch_mb", 4096),
+    )));
+    batch_system.set_default_limits(TaskLimits {
+        wall_time: Some(10 * blog_os::timer::TICKS_PER_SECOND),
+        cpu_time: None,
+    });
+    println!("  [OK] Batch system initialized with 4 CPUs, 1024KB memory, 2 licences and 4096MB scratch\n");
+
+    // Boost waiting jobs one priority level per second, so low-priority
+    // sweeps still run while the pipeline keeps submitting
+    batch_system.set_aging_policy(AgingPolicy {
+        interval: blog_os::timer::TICKS_PER_SECOND,
+        boost: 1,
+        max_priority: 10,
+    });
+
+    // Favour accounts that have used little CPU time in the last minute
+    batch_system.set_fair_share_policy(FairSharePolicy {
+        half_life: 60 * blog_os::timer::TICKS_PER_SECOND,
+        weight: 5,
+    });
+
+    // Jobs that request resources while running must declare their maximum
+    batch_system.set_allocation_policy(AllocationPolicy::Banker);
+
+    // Keep the research account's sweeps from crowding out the pipeline
+    batch_system.set_quota("research", AccountQuota {
+        max_cpus: Some(1),
+        max_queued_jobs: Some(8),
+        ..AccountQuota::default()
     });
-    println!("  [OK] Batch system initialized with 4 CPUs and 1024KB memory\n");
-
-    // Create test tasks
-    println!("Creating Test Tasks:");
-    let task1 = Task {
-        executable: alloc::string::String::from("task1"),
-        arguments: alloc::vec![alloc::string::String::from("arg1")],
-        priority: 1,
-        resource_requirements: ResourceRequirements {
-            cpu: 1,
-            memory: 256,
-        },
-        status: TaskStatus::Queued,
-    };
-    println!("  [+] Created Task 1 (Priority: 1, Memory: 256KB)");
-
-    let task2 = Task {
-        executable: alloc::string::String::from("task2"),
-        arguments: alloc::vec![alloc::string::String::from("arg2")],
-        priority: 2,
-        resource_requirements: ResourceRequirements {
-            cpu: 1,
-            memory: 512,
-        },
-        status: TaskStatus::Queued,
-    };
-    println!("  [+] Created Task 2 (Priority: 2, Memory: 512KB)");
-
-    let task3 = Task {
-        executable: alloc::string::String::from("task3"),
-        arguments: alloc::vec![alloc::string::String::from("arg3")],
-        priority: 3,
-        resource_requirements: ResourceRequirements {
-            cpu: 2,
-            memory: 384,
-        },
-        status: TaskStatus::Queued,
-    };
-    println!("  [+] Created Task 3 (Priority: 3, Memory: 384KB)");
-
-    let task4 = Task {
-        executable: alloc::string::String::from("task4"),
-        arguments: alloc::vec![alloc::string::String::from("arg4")],
-        priority: 4,
-        resource_requirements: ResourceRequirements {
-            cpu: 1,
-            memory: 128,
-        },
-        status: TaskStatus::Queued,
-    };
-    println!("  [+] Created Task 4 (Priority: 4, Memory: 128KB)\n");
-
-    // Submit and run tasks
-    println!("Submitting tasks to batch system...");
-    batch_system.submit_task(task1);
-    batch_system.submit_task(task2);
-    batch_system.submit_task(task3);
-    batch_system.submit_task(task4);
-    println!("  [OK] Tasks submitted successfully\n");
+
+    // Guarantee the ops account capacity for its maintenance run
+    let maintenance_start = blog_os::timer::ticks() + 2 * blog_os::timer::TICKS_PER_SECOND;
+    batch_system.create_reservation(AdvanceReservation::new(
+        "maintenance",
+        "ops",
+        ResourceRequirements::new(2, 256),
+        maintenance_start,
+        5 * blog_os::timer::TICKS_PER_SECOND,
+    ));
+
+    // Submit the jobs described in the boot job file
+    println!("Loading boot job file...");
+    match blog_os::job_file::load(batch_system, BOOT_JOBS) {
+        Ok(count) => println!("  [OK] Loaded {} job definitions from boot job file\n", count),
+        Err(err) => println!("  [ERROR] Invalid boot job file: {}\n", err),
+    }
+
+    println!("Starting secondary harts...");
+    let harts = blog_os::smp::start_secondary_harts(batch_system);
+    println!("  [OK] {} of {} harts online\n", harts, blog_os::smp::MAX_HARTS);
 
     println!("Starting batch system execution...");
     batch_system.run();
     println!("  [OK] All tasks completed successfully\n");
 
+    #[cfg(feature = "lockdep")]
+    blog_os::lockdep::print_summary();
+
     // Enter main loop
     println!("Kernel initialization complete!");
     println!("Entering main loop...\n");
 
-    loop {
-        unsafe {
-            // Wait for interrupts, then handle them
-            riscv::asm::wfi();
-        }
-    }
+    // Wait for interrupts, then handle them
+    blog_os::smp::idle_loop()
 }
 
 #[panic_handler]
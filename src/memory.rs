@@ -1,5 +1,5 @@
 use core::sync::atomic::{AtomicBool, Ordering};
-use spin::Mutex;
+use crate::irq_lock::IrqSafeMutex;
 use crate::println;
 
 pub const HEAP_START: usize = 0x80400000;
@@ -53,7 +53,7 @@ impl MemoryManager {
 }
 
 lazy_static::lazy_static! {
-    pub static ref MEMORY_MANAGER: Mutex<MemoryManager> = Mutex::new(MemoryManager::new());
+    pub static ref MEMORY_MANAGER: IrqSafeMutex<MemoryManager> = IrqSafeMutex::new(MemoryManager::new());
 }
 
 pub fn init() {
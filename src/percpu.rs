@@ -0,0 +1,123 @@
+use alloc::collections::VecDeque;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use crate::batch_system::ActiveThread;
+use crate::ipi::RemoteCall;
+use crate::irq_lock::{IrqSafeMutex, SpinMutex};
+use crate::smp::MAX_HARTS;
+use crate::task::JobId;
+
+const NO_JOB: u64 = 0;
+
+/// State private to one hart, reached through its `tp` register. Other harts
+/// only touch it to queue work or IPIs, so everything is atomic or locked.
+pub struct PerCpu {
+    pub hart_id: usize,
+    pub online: AtomicBool,
+    /// Job whose time slice is in progress on this hart
+    current_job: AtomicU64,
+    /// Threads this hart gives time slices to, in round-robin order
+    pub run_queue: SpinMutex<VecDeque<ActiveThread>>,
+    /// Timer interrupts taken by this hart
+    pub ticks: AtomicU64,
+    /// Traps this hart is currently inside
+    nesting: AtomicUsize,
+    /// Time slices the hart has run
+    pub slices: AtomicU64,
+    /// Jobs this hart took from other harts' run queues
+    pub steals: AtomicU64,
+    /// Bitmask of IPI reasons waiting to be handled
+    pub(crate) ipi_pending: AtomicU32,
+    /// Functions other harts asked this hart to run
+    pub(crate) remote_calls: IrqSafeMutex<VecDeque<RemoteCall>>,
+}
+
+impl PerCpu {
+    const fn new(hart_id: usize) -> Self {
+        PerCpu {
+            hart_id,
+            online: AtomicBool::new(false),
+            current_job: AtomicU64::new(NO_JOB),
+            run_queue: SpinMutex::new(VecDeque::new()),
+            ticks: AtomicU64::new(0),
+            nesting: AtomicUsize::new(0),
+            slices: AtomicU64::new(0),
+            steals: AtomicU64::new(0),
+            ipi_pending: AtomicU32::new(0),
+            remote_calls: IrqSafeMutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn current_job(&self) -> Option<JobId> {
+        match self.current_job.load(Ordering::SeqCst) {
+            NO_JOB => None,
+            job => Some(JobId(job)),
+        }
+    }
+
+    pub fn set_current_job(&self, job: Option<JobId>) {
+        self.current_job.store(job.map_or(NO_JOB, |job| job.0), Ordering::SeqCst);
+    }
+
+    /// Clears the current job if it is `job`
+    pub fn clear_current_job(&self, job: JobId) {
+        let _ = self.current_job.compare_exchange(job.0, NO_JOB, Ordering::SeqCst, Ordering::SeqCst);
+    }
+
+    /// Threads on the run queue plus the one in its time slice, if any
+    pub fn load(&self) -> usize {
+        self.run_queue.lock().len() + self.current_job().is_some() as usize
+    }
+
+    pub fn nesting(&self) -> usize {
+        self.nesting.load(Ordering::Relaxed)
+    }
+}
+
+static CPUS: [PerCpu; MAX_HARTS] = {
+    let mut cpus = [const { PerCpu::new(0) }; MAX_HARTS];
+    let mut id = 0;
+    while id < MAX_HARTS {
+        // The placeholder has nothing to drop, and destructors can't run in a const
+        core::mem::forget(core::mem::replace(&mut cpus[id], PerCpu::new(id)));
+        id += 1;
+    }
+    cpus
+};
+
+/// Points the calling hart's `tp` at its per-CPU area. Must run before
+/// anything calls `this`, including the trap handler.
+pub fn init(hart_id: usize) {
+    let cpu: *const PerCpu = &CPUS[hart_id];
+    unsafe { core::arch::asm!("mv tp, {}", in(reg) cpu) };
+}
+
+/// The calling hart's per-CPU area
+pub fn this() -> &'static PerCpu {
+    let cpu: *const PerCpu;
+    unsafe {
+        core::arch::asm!("mv {}, tp", out(reg) cpu);
+        &*cpu
+    }
+}
+
+pub fn cpu(hart_id: usize) -> &'static PerCpu {
+    &CPUS[hart_id]
+}
+
+pub fn all() -> impl Iterator<Item = &'static PerCpu> {
+    CPUS.iter()
+}
+
+/// Marks entry to a trap handler on the calling hart
+pub fn enter_trap() {
+    this().nesting.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn exit_trap() {
+    this().nesting.fetch_sub(1, Ordering::Relaxed);
+}
+
+/// Whether the calling hart is running a trap handler
+pub fn in_interrupt() -> bool {
+    this().nesting() > 0
+}
@@ -0,0 +1,450 @@
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use crate::executor::{JobContext, FD_STDERR, FD_STDOUT};
+use crate::futex::{FUTEX_WAIT, FUTEX_WAKE};
+use crate::syscall::{
+    syscall, SYSCALL_BRK, SYSCALL_EXIT, SYSCALL_FUTEX, SYSCALL_GETTID, SYSCALL_MUTEX_CREATE,
+    SYSCALL_MUTEX_LOCK, SYSCALL_MUTEX_UNLOCK, SYSCALL_RESOURCE_RELEASE, SYSCALL_RESOURCE_REQUEST,
+    SYSCALL_SEMAPHORE_CREATE, SYSCALL_SEMAPHORE_DOWN, SYSCALL_SEMAPHORE_UP, SYSCALL_THREAD_CREATE,
+    SYSCALL_WAITTID, SYSCALL_WRITE,
+};
+
+/// Spin iterations making up one step of simulated work
+const WORK_PER_STEP: u64 = 5_000;
+
+/// Mutex keys of the dining philosophers' forks start here
+const FORK_KEY_BASE: usize = 100;
+
+/// Most worker threads a program starts
+const MAX_WORKERS: usize = 4;
+
+/// Ticks a `futex_lock` worker waits for the lock before trying again
+const FUTEX_TIMEOUT: u64 = 5;
+
+/// A program built into the kernel image. The batch system calls `step`
+/// repeatedly, each call doing a slice of work, until the program's main
+/// thread exits. Threads it creates are stepped the same way from their own
+/// entry function. A step that makes a blocking syscall must return straight
+/// after it; the next step runs once the call has completed.
+pub struct Program {
+    pub name: &'static str,
+    pub step: fn(&mut JobContext),
+}
+
+static PROGRAMS: &[Program] = &[
+    Program { name: "compute", step: compute },
+    Program { name: "echo", step: echo },
+    Program { name: "exit", step: exit_with },
+    Program { name: "fault", step: fault },
+    Program { name: "producer", step: producer },
+    Program { name: "consumer", step: consumer },
+    Program { name: "philosopher", step: philosopher },
+    Program { name: "threads", step: threads },
+    Program { name: "futex_lock", step: futex_lock },
+    Program { name: "acquire", step: acquire },
+];
+
+pub fn lookup(name: &str) -> Option<&'static Program> {
+    PROGRAMS.iter().find(|program| program.name == name)
+}
+
+/// Functions a program can start threads at, by the index it passes to
+/// `thread_create`
+static THREAD_ENTRIES: &[fn(&mut JobContext)] = &[worker, futex_worker];
+
+const WORKER_ENTRY: usize = 0;
+const FUTEX_WORKER_ENTRY: usize = 1;
+
+pub fn thread_entry(index: usize) -> Option<fn(&mut JobContext)> {
+    THREAD_ENTRIES.get(index).copied()
+}
+
+fn write(ctx: &mut JobContext, fd: usize, text: &str) {
+    // The string outlives the call
+    unsafe { syscall(ctx, SYSCALL_WRITE, [fd, text.as_ptr() as usize, text.len(), 0]) };
+}
+
+fn exit(ctx: &mut JobContext, exit_code: i32) {
+    unsafe { syscall(ctx, SYSCALL_EXIT, [exit_code as usize, 0, 0, 0]) };
+}
+
+fn brk(ctx: &mut JobContext, size: usize) -> bool {
+    unsafe { syscall(ctx, SYSCALL_BRK, [size, 0, 0, 0]) >= 0 }
+}
+
+fn mutex_create(ctx: &mut JobContext, key: usize) {
+    unsafe { syscall(ctx, SYSCALL_MUTEX_CREATE, [key, 0, 0, 0]) };
+}
+
+fn mutex_lock(ctx: &mut JobContext, key: usize) {
+    unsafe { syscall(ctx, SYSCALL_MUTEX_LOCK, [key, 0, 0, 0]) };
+}
+
+fn mutex_unlock(ctx: &mut JobContext, key: usize) {
+    unsafe { syscall(ctx, SYSCALL_MUTEX_UNLOCK, [key, 0, 0, 0]) };
+}
+
+fn semaphore_create(ctx: &mut JobContext, key: usize, count: usize) {
+    unsafe { syscall(ctx, SYSCALL_SEMAPHORE_CREATE, [key, count, 0, 0]) };
+}
+
+fn semaphore_up(ctx: &mut JobContext, key: usize) {
+    unsafe { syscall(ctx, SYSCALL_SEMAPHORE_UP, [key, 0, 0, 0]) };
+}
+
+fn semaphore_down(ctx: &mut JobContext, key: usize) {
+    unsafe { syscall(ctx, SYSCALL_SEMAPHORE_DOWN, [key, 0, 0, 0]) };
+}
+
+fn futex_wait(ctx: &mut JobContext, word: &AtomicU32, expected: u32, timeout: u64) {
+    let args = [word.as_ptr() as usize, FUTEX_WAIT, expected as usize, timeout as usize];
+    // The word is borrowed for the whole call
+    unsafe { syscall(ctx, SYSCALL_FUTEX, args) };
+}
+
+fn futex_wake(ctx: &mut JobContext, word: &AtomicU32, count: usize) {
+    unsafe { syscall(ctx, SYSCALL_FUTEX, [word.as_ptr() as usize, FUTEX_WAKE, count, 0]) };
+}
+
+fn resource_request(ctx: &mut JobContext, name: &str, amount: u64) -> bool {
+    let args = [name.as_ptr() as usize, name.len(), amount as usize, 0];
+    // The string outlives the call
+    unsafe { syscall(ctx, SYSCALL_RESOURCE_REQUEST, args) >= 0 }
+}
+
+fn resource_release(ctx: &mut JobContext, name: &str, amount: u64) {
+    let args = [name.as_ptr() as usize, name.len(), amount as usize, 0];
+    // The string outlives the call
+    unsafe { syscall(ctx, SYSCALL_RESOURCE_RELEASE, args) };
+}
+
+fn thread_create(ctx: &mut JobContext, entry: usize, arg: usize) -> isize {
+    unsafe { syscall(ctx, SYSCALL_THREAD_CREATE, [entry, arg, 0, 0]) }
+}
+
+fn gettid(ctx: &mut JobContext) -> usize {
+    unsafe { syscall(ctx, SYSCALL_GETTID, [0, 0, 0, 0]) as usize }
+}
+
+fn waittid(ctx: &mut JobContext, tid: usize) -> isize {
+    unsafe { syscall(ctx, SYSCALL_WAITTID, [tid, 0, 0, 0]) }
+}
+
+fn argument<T: core::str::FromStr>(ctx: &JobContext, idx: usize) -> Option<T> {
+    ctx.arguments.get(idx).and_then(|arg| arg.parse().ok())
+}
+
+/// `compute [steps] [memory_kb]`: spins for the given number of steps,
+/// reporting progress and growing its working set evenly to `memory_kb`
+fn compute(ctx: &mut JobContext) {
+    let total: u64 = argument(ctx, 0).unwrap_or(10).max(1);
+    let working_set: u64 = argument(ctx, 1).unwrap_or(0);
+    for _ in 0..WORK_PER_STEP {
+        core::hint::spin_loop();
+    }
+
+    let size = working_set * 1024 * (ctx.steps + 1).min(total) / total;
+    if !brk(ctx, size as usize) {
+        write(ctx, FD_STDERR, &format!("out of memory growing to {}KB\n", size / 1024));
+        exit(ctx, 1);
+        return;
+    }
+
+    let done = ctx.steps + 1;
+    write(ctx, FD_STDOUT, &format!("Progress: {}%\n", done * 100 / total));
+    if done >= total {
+        exit(ctx, 0);
+    }
+}
+
+/// `echo [args...]`: writes its arguments to stdout
+fn echo(ctx: &mut JobContext) {
+    let mut line = ctx.arguments.join(" ");
+    line.push('\n');
+    write(ctx, FD_STDOUT, &line);
+    exit(ctx, 0);
+}
+
+/// `exit [code]`: exits with the given code (1 by default)
+fn exit_with(ctx: &mut JobContext) {
+    let exit_code: i32 = argument(ctx, 0).unwrap_or(1);
+    write(ctx, FD_STDERR, &format!("exiting with code {}\n", exit_code));
+    exit(ctx, exit_code);
+}
+
+/// `fault`: simulates a store to an unmapped address
+fn fault(ctx: &mut JobContext) {
+    write(ctx, FD_STDOUT, "about to write through a null pointer\n");
+    ctx.fault(String::from("StoreFault accessing 0x0"));
+}
+
+// Locals used by the programs below
+const PHASE: usize = 0;
+const DONE: usize = 1;
+
+/// Creates the semaphores counting free and filled slots of the bounded
+/// buffer at `key`, returning their keys
+fn bounded_buffer(ctx: &mut JobContext) -> (usize, usize) {
+    let key: usize = argument(ctx, 0).unwrap_or(0);
+    let capacity: usize = argument(ctx, 2).unwrap_or(2);
+    let (free, filled) = (key, key + 1);
+    if ctx.steps == 0 {
+        semaphore_create(ctx, free, capacity);
+        semaphore_create(ctx, filled, 0);
+    }
+    (free, filled)
+}
+
+/// `producer KEY ITEMS [CAPACITY]`: puts ITEMS items into the bounded buffer
+/// shared with the `consumer` using the same KEY, waiting while it is full
+fn producer(ctx: &mut JobContext) {
+    let items: usize = argument(ctx, 1).unwrap_or(4);
+    let (free, filled) = bounded_buffer(ctx);
+    match ctx.locals[PHASE] {
+        0 => {
+            ctx.locals[PHASE] = 1;
+            semaphore_down(ctx, free);
+        }
+        _ => {
+            write(ctx, FD_STDOUT, &format!("produced item {}\n", ctx.locals[DONE]));
+            semaphore_up(ctx, filled);
+            ctx.locals[DONE] += 1;
+            ctx.locals[PHASE] = 0;
+            if ctx.locals[DONE] == items {
+                exit(ctx, 0);
+            }
+        }
+    }
+}
+
+/// `consumer KEY ITEMS [CAPACITY]`: takes ITEMS items out of the bounded
+/// buffer shared with the `producer` using the same KEY, waiting while it is empty
+fn consumer(ctx: &mut JobContext) {
+    let items: usize = argument(ctx, 1).unwrap_or(4);
+    let (free, filled) = bounded_buffer(ctx);
+    match ctx.locals[PHASE] {
+        0 => {
+            ctx.locals[PHASE] = 1;
+            semaphore_down(ctx, filled);
+        }
+        _ => {
+            write(ctx, FD_STDOUT, &format!("consumed item {}\n", ctx.locals[DONE]));
+            semaphore_up(ctx, free);
+            ctx.locals[DONE] += 1;
+            ctx.locals[PHASE] = 0;
+            if ctx.locals[DONE] == items {
+                exit(ctx, 0);
+            }
+        }
+    }
+}
+
+/// `philosopher SEAT SEATS MEALS`: eats MEALS meals at a table of SEATS,
+/// each needing the forks (mutexes) either side. Forks are picked up lower
+/// number first, so the table can't deadlock.
+fn philosopher(ctx: &mut JobContext) {
+    let seat: usize = argument(ctx, 0).unwrap_or(0);
+    let seats: usize = argument(ctx, 1).unwrap_or(5).max(2);
+    let meals: usize = argument(ctx, 2).unwrap_or(3);
+    let (left, right) = (FORK_KEY_BASE + seat, FORK_KEY_BASE + (seat + 1) % seats);
+    let (first, second) = (left.min(right), left.max(right));
+    if ctx.steps == 0 {
+        mutex_create(ctx, first);
+        mutex_create(ctx, second);
+    }
+
+    match ctx.locals[PHASE] {
+        0 => {
+            ctx.locals[PHASE] = 1;
+            mutex_lock(ctx, first);
+        }
+        1 => {
+            ctx.locals[PHASE] = 2;
+            mutex_lock(ctx, second);
+        }
+        _ => {
+            ctx.locals[DONE] += 1;
+            write(ctx, FD_STDOUT, &format!("philosopher {} eats meal {}\n", seat, ctx.locals[DONE]));
+            mutex_unlock(ctx, second);
+            mutex_unlock(ctx, first);
+            ctx.locals[PHASE] = 0;
+            if ctx.locals[DONE] == meals {
+                exit(ctx, 0);
+            }
+        }
+    }
+}
+
+// Locals of a main thread waiting for workers: worker TIDs follow the count
+const WORKERS: usize = 2;
+const WORKER_TIDS: usize = 3;
+
+/// Starts `count` threads at thread entry point `entry` (at most
+/// `MAX_WORKERS`), passing each `arg` of its number from 1
+fn spawn_workers(ctx: &mut JobContext, count: usize, entry: usize, arg: impl Fn(usize) -> usize) {
+    let count = count.clamp(1, MAX_WORKERS);
+    for idx in 0..count {
+        let tid = thread_create(ctx, entry, arg(idx + 1));
+        ctx.locals[WORKER_TIDS + idx] = tid.max(0) as usize;
+    }
+    ctx.locals[WORKERS] = count;
+}
+
+/// Collects the exit codes of workers in turn, summing them in `DONE`, and
+/// returns whether they all have exited. Waits for the first still running.
+fn join_workers(ctx: &mut JobContext) -> bool {
+    for idx in 0..ctx.locals[WORKERS] {
+        let tid = ctx.locals[WORKER_TIDS + idx];
+        if tid == 0 {
+            continue;
+        }
+        let exit_code = waittid(ctx, tid);
+        if exit_code == -2 {
+            return false;
+        }
+        if exit_code >= 0 {
+            write(ctx, FD_STDOUT, &format!("thread {} exited with code {}\n", tid, exit_code));
+            ctx.locals[DONE] += exit_code as usize;
+        }
+        ctx.locals[WORKER_TIDS + idx] = 0;
+    }
+    ctx.locals[WORKER_TIDS..WORKER_TIDS + ctx.locals[WORKERS]].iter().all(|&tid| tid == 0)
+}
+
+/// `threads [WORKERS] [STEPS]`: starts up to four worker threads that each
+/// compute for STEPS steps, then waits for them all and sums their exit codes
+fn threads(ctx: &mut JobContext) {
+    if ctx.steps == 0 {
+        let workers: usize = argument(ctx, 0).unwrap_or(2);
+        spawn_workers(ctx, workers, WORKER_ENTRY, |number| number);
+        return;
+    }
+    if join_workers(ctx) {
+        write(ctx, FD_STDOUT, &format!("all workers done, exit codes sum to {}\n", ctx.locals[DONE]));
+        exit(ctx, 0);
+    }
+}
+
+/// Worker thread of `threads`: computes for STEPS steps, then exits with
+/// the number it was started with
+fn worker(ctx: &mut JobContext) {
+    let total: u64 = argument(ctx, 1).unwrap_or(3).max(1);
+    for _ in 0..WORK_PER_STEP {
+        core::hint::spin_loop();
+    }
+
+    let tid = gettid(ctx);
+    write(ctx, FD_STDOUT, &format!("worker {} (thread {}) step {}\n", ctx.arg, tid, ctx.steps + 1));
+    if ctx.steps + 1 >= total {
+        exit(ctx, ctx.arg as i32);
+    }
+}
+
+/// State a `futex_lock` job's threads share, allocated by its main thread
+#[derive(Default)]
+struct FutexLock {
+    /// 0 unlocked, 1 locked, 2 locked with waiters
+    word: AtomicU32,
+    /// Counter the lock protects, updated without an atomic read-modify-write
+    counter: AtomicUsize,
+    /// Times a worker had to enter the kernel to wait
+    waits: AtomicUsize,
+}
+
+// Local of the `futex_lock` main thread holding its `FutexLock`
+const LOCK: usize = WORKER_TIDS + MAX_WORKERS;
+
+/// `futex_lock [WORKERS] [ROUNDS]`: workers each increment a shared counter
+/// ROUNDS times under a lock built on a futex, entering the kernel only
+/// when the lock is contended. Exits 1 if any increment was lost.
+fn futex_lock(ctx: &mut JobContext) {
+    let rounds: usize = argument(ctx, 1).unwrap_or(3);
+    if ctx.steps == 0 {
+        // Each job gets its own lock, so its futex address is its own too
+        let lock = Box::into_raw(Box::<FutexLock>::default()) as usize;
+        ctx.locals[LOCK] = lock;
+        let workers: usize = argument(ctx, 0).unwrap_or(3);
+        spawn_workers(ctx, workers, FUTEX_WORKER_ENTRY, |_| lock);
+        return;
+    }
+    if !join_workers(ctx) {
+        return;
+    }
+
+    // Every worker has exited, so nothing else refers to the lock
+    let lock = unsafe { Box::from_raw(ctx.locals[LOCK] as *mut FutexLock) };
+    let counter = lock.counter.load(Ordering::SeqCst);
+    let expected = ctx.locals[WORKERS] * rounds;
+    write(ctx, FD_STDOUT, &format!("counter {} of {} after {} futex waits\n",
+        counter, expected, lock.waits.load(Ordering::SeqCst)));
+    exit(ctx, (counter != expected) as i32);
+}
+
+/// Worker thread of `futex_lock`, passed its job's `FutexLock`. Takes the
+/// lock in one step and holds it into the next, so the other workers find
+/// it contended.
+fn futex_worker(ctx: &mut JobContext) {
+    let rounds: usize = argument(ctx, 1).unwrap_or(3);
+    // The main thread frees the lock only once this thread has exited
+    let lock = unsafe { &*(ctx.arg as *const FutexLock) };
+    match ctx.locals[PHASE] {
+        0 => {
+            if lock.word.compare_exchange(0, 1, Ordering::Acquire, Ordering::Relaxed).is_ok()
+                || lock.word.swap(2, Ordering::Acquire) == 0
+            {
+                ctx.locals[PHASE] = 1;
+                return;
+            }
+            if core::mem::take(&mut ctx.timed_out) {
+                let tid = gettid(ctx);
+                write(ctx, FD_STDOUT, &format!("thread {} timed out waiting, retrying\n", tid));
+            }
+            lock.waits.fetch_add(1, Ordering::Relaxed);
+            futex_wait(ctx, &lock.word, 2, FUTEX_TIMEOUT);
+        }
+        _ => {
+            let counter = lock.counter.load(Ordering::Relaxed);
+            lock.counter.store(counter + 1, Ordering::Relaxed);
+            ctx.locals[DONE] += 1;
+            if lock.word.swap(0, Ordering::Release) == 2 {
+                futex_wake(ctx, &lock.word, 1);
+            }
+            ctx.locals[PHASE] = 0;
+            if ctx.locals[DONE] == rounds {
+                exit(ctx, 0);
+            }
+        }
+    }
+}
+
+/// `acquire NAME AMOUNT [NAME AMOUNT...]`: requests each resource in turn
+/// while running, one per step, holding what it has while it waits for the
+/// next; then works for a step and releases them all. Exits 1 if a request
+/// is refused.
+fn acquire(ctx: &mut JobContext) {
+    let pairs = ctx.arguments.len() / 2;
+    let requested = ctx.locals[DONE];
+    if requested < pairs {
+        let name = ctx.arguments[2 * requested].clone();
+        let amount: u64 = argument(ctx, 2 * requested + 1).unwrap_or(1);
+        write(ctx, FD_STDOUT, &format!("requesting {} {}\n", amount, name));
+        ctx.locals[DONE] += 1;
+        if !resource_request(ctx, &name, amount) {
+            exit(ctx, 1);
+        }
+        return;
+    }
+
+    for _ in 0..WORK_PER_STEP {
+        core::hint::spin_loop();
+    }
+    for idx in 0..pairs {
+        let name = ctx.arguments[2 * idx].clone();
+        let amount: u64 = argument(ctx, 2 * idx + 1).unwrap_or(1);
+        resource_release(ctx, &name, amount);
+    }
+    write(ctx, FD_STDOUT, "released everything\n");
+    exit(ctx, 0);
+}
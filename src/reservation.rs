@@ -0,0 +1,55 @@
+use alloc::string::String;
+use crate::task::ResourceRequirements;
+
+/// Capacity set aside for a future time window. Only jobs that name the
+/// reservation and belong to its owner may use the reserved resources, and
+/// other jobs are kept off them for the whole window.
+#[derive(Debug, Clone)]
+pub struct AdvanceReservation {
+    pub name: String,
+    /// Account whose jobs may use the reservation
+    pub owner: String,
+    pub resources: ResourceRequirements,
+    pub start_tick: u64,
+    /// Length of the window in ticks
+    pub duration: u64,
+    /// Resources held by the reservation's running jobs
+    pub in_use: ResourceRequirements,
+}
+
+impl AdvanceReservation {
+    pub fn new(name: &str, owner: &str, resources: ResourceRequirements, start_tick: u64, duration: u64) -> Self {
+        AdvanceReservation {
+            name: String::from(name),
+            owner: String::from(owner),
+            resources,
+            start_tick,
+            duration,
+            in_use: ResourceRequirements::new(0, 0),
+        }
+    }
+
+    pub fn end_tick(&self) -> u64 {
+        self.start_tick.saturating_add(self.duration)
+    }
+
+    pub fn is_active(&self, now: u64) -> bool {
+        self.start_tick <= now && now < self.end_tick()
+    }
+
+    pub fn has_ended(&self, now: u64) -> bool {
+        now >= self.end_tick()
+    }
+
+    /// Whether the window intersects `[start, end)`
+    pub fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start_tick < end && start < self.end_tick()
+    }
+
+    /// Reserved resources not taken by the reservation's own jobs
+    pub fn unused(&self) -> ResourceRequirements {
+        let mut unused = self.resources.clone();
+        unused.subtract(&self.in_use);
+        unused
+    }
+}
@@ -1,8 +1,76 @@
-use crate::task::ResourceRequirements;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::ops::Range;
+use crate::account::{Account, AccountQuota, FairShareFactors, FairSharePolicy};
+use crate::reservation::AdvanceReservation;
+use crate::task::{JobId, ResourceRequirements, Task, Tid};
+use crate::timer;
 
+/// How the manager decides whether to grant resources
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationPolicy {
+    /// Grant anything that fits in what is free
+    #[default]
+    Greedy,
+    /// Banker's algorithm: jobs may never hold more than their declared
+    /// maximum, and a job is only started or a request granted if every
+    /// running job could still get its maximum and finish afterwards
+    Banker,
+    /// Grant greedily, but look for jobs deadlocked on each other's resources
+    Detect,
+}
+
+/// Resources a running job holds
+struct Claim {
+    account: String,
+    /// What the job was started with
+    base: ResourceRequirements,
+    /// Base plus everything granted since
+    allocated: ResourceRequirements,
+    max: ResourceRequirements,
+}
+
+/// A request from a running thread that hasn't been granted yet
+struct PendingRequest {
+    job: JobId,
+    tid: Tid,
+    request: ResourceRequirements,
+}
+
+/// Why a running job's request was refused outright
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestError {
+    /// The job isn't running
+    UnknownJob,
+    /// More than the machine has in total
+    ExceedsCapacity,
+    /// More than the job's declared maximum, under the banker's algorithm
+    ExceedsClaim,
+    /// A release of more than the job was granted while running
+    NotHeld,
+}
+
+/// Tracks the machine's CPUs, memory and any generic resources registered in
+/// `total_resources` when it is constructed. A request is granted only if
+/// every resource it asks for is available, the requesting account stays
+/// within its quota, and no advance reservation the job doesn't belong to
+/// needs those resources while the job is expected to run.
 pub struct ResourceManager {
     pub total_resources: ResourceRequirements,
     pub available_resources: ResourceRequirements,
+    accounts: BTreeMap<String, Account>,
+    fair_share_policy: FairSharePolicy,
+    reservations: Vec<AdvanceReservation>,
+    policy: AllocationPolicy,
+    claims: BTreeMap<JobId, Claim>,
+    /// Requests waiting for resources, granted in arrival order
+    pending: VecDeque<PendingRequest>,
+    /// Set whenever a request waits or is granted, so deadlock detection
+    /// only reruns once something has changed
+    waits_changed: bool,
+    /// Jobs the last detection pass found deadlocked
+    deadlocked: Vec<JobId>,
 }
 
 impl ResourceManager {
@@ -10,27 +78,452 @@ impl ResourceManager {
         ResourceManager {
             total_resources: total_resources.clone(),
             available_resources: total_resources,
+            accounts: BTreeMap::new(),
+            fair_share_policy: FairSharePolicy::default(),
+            reservations: Vec::new(),
+            policy: AllocationPolicy::default(),
+            claims: BTreeMap::new(),
+            pending: VecDeque::new(),
+            waits_changed: false,
+            deadlocked: Vec::new(),
+        }
+    }
+
+    pub fn set_allocation_policy(&mut self, policy: AllocationPolicy) {
+        self.policy = policy;
+    }
+
+    pub fn allocation_policy(&self) -> AllocationPolicy {
+        self.policy
+    }
+
+    pub fn set_quota(&mut self, account: &str, quota: AccountQuota) {
+        self.account_mut(account).quota = quota;
+    }
+
+    pub fn get_quota(&self, account: &str) -> AccountQuota {
+        self.accounts.get(account).map(|a| a.quota.clone()).unwrap_or_default()
+    }
+
+    pub fn set_fair_share_policy(&mut self, policy: FairSharePolicy) {
+        self.fair_share_policy = policy;
+    }
+
+    fn account_mut(&mut self, account: &str) -> &mut Account {
+        self.accounts
+            .entry(String::from(account))
+            .or_insert_with(|| Account::new(AccountQuota::default(), timer::ticks()))
+    }
+
+    /// Sets capacity aside for a future window, returning false if the name
+    /// is taken or the machine can't hold it alongside overlapping reservations
+    pub fn create_reservation(&mut self, reservation: AdvanceReservation) -> bool {
+        if self.reservations.iter().any(|r| r.name == reservation.name) {
+            return false;
         }
+        let mut committed = reservation.resources.clone();
+        for other in &self.reservations {
+            if other.overlaps(reservation.start_tick, reservation.end_tick()) {
+                committed.add(&other.resources);
+            }
+        }
+        if !committed.fits_within(&self.total_resources) {
+            return false;
+        }
+        self.reservations.push(reservation);
+        true
     }
 
-    pub fn allocate_resources(&mut self, requirements: &ResourceRequirements) -> bool {
-        if requirements.cpu <= self.available_resources.cpu
-            && requirements.memory <= self.available_resources.memory
+    pub fn get_reservations(&self) -> &[AdvanceReservation] {
+        &self.reservations
+    }
+
+    fn reservation_mut(&mut self, name: &str) -> Option<&mut AdvanceReservation> {
+        self.reservations.iter_mut().find(|r| r.name == name)
+    }
+
+    /// Earliest tick after `now` at which a reservation starts or ends
+    pub fn next_reservation_boundary(&self, now: u64) -> Option<u64> {
+        self.reservations
+            .iter()
+            .flat_map(|r| [r.start_tick, r.end_tick()])
+            .filter(|&tick| tick > now)
+            .min()
+    }
+
+    /// Unused capacity of reservations other than the task's own that overlap `window`
+    fn reserved_for_others(&self, task: &Task, window: &Range<u64>) -> ResourceRequirements {
+        let mut reserved = ResourceRequirements::new(0, 0);
+        for r in &self.reservations {
+            if r.overlaps(window.start, window.end) && task.reservation.as_ref() != Some(&r.name) {
+                reserved.add(&r.unused());
+            }
+        }
+        reserved
+    }
+
+    /// Allocates job `id`'s resources for the ticks in `window`, its expected run
+    pub fn allocate_resources(&mut self, id: JobId, task: &Task, window: Range<u64>) -> bool {
+        if !self.can_allocate(task, window) {
+            return false;
+        }
+        let requirements = &task.resource_requirements;
+        self.available_resources.subtract(requirements);
+        self.account_mut(&task.account).allocate(requirements);
+        if let Some(reservation) = task.reservation.as_ref().and_then(|name| self.reservation_mut(name)) {
+            reservation.in_use.add(requirements);
+        }
+        self.claims.insert(id, Claim {
+            account: task.account.clone(),
+            base: requirements.clone(),
+            allocated: requirements.clone(),
+            max: task.max_claim(),
+        });
+        true
+    }
+
+    /// Returns everything job `id` holds, including resources granted while
+    /// it ran, and drops its waiting requests. Returns false, changing
+    /// nothing, if that would free more than the machine, the account or the
+    /// reservation has allocated.
+    pub fn release_resources(&mut self, id: JobId, task: &Task) -> bool {
+        let requirements = self
+            .claims
+            .get(&id)
+            .map_or_else(|| task.resource_requirements.clone(), |claim| claim.allocated.clone());
+        let requirements = &requirements;
+        let mut available = self.available_resources.clone();
+        available.add(requirements);
+        let account_holds = self.accounts.get(&task.account).is_some_and(|a| a.holds(requirements));
+        let reservation_holds = match &task.reservation {
+            Some(name) => self
+                .reservations
+                .iter()
+                .any(|r| &r.name == name && task.resource_requirements.fits_within(&r.in_use)),
+            None => true,
+        };
+        if !available.fits_within(&self.total_resources) || !account_holds || !reservation_holds {
+            return false;
+        }
+
+        self.available_resources = available;
+        self.account_mut(&task.account).release(requirements);
+        if let Some(reservation) = task.reservation.as_ref().and_then(|name| self.reservation_mut(name)) {
+            // Reservations only ever hold what their jobs start with
+            reservation.in_use.subtract(&task.resource_requirements);
+        }
+        self.claims.remove(&id);
+        self.pending.retain(|pending| pending.job != id);
+        self.waits_changed = true;
+        true
+    }
+
+    /// Asks for more resources on behalf of thread `tid` of running job
+    /// `job`. Returns true if they were granted, or false if the thread must
+    /// wait for `grant_pending` to grant them.
+    pub fn request_resources(&mut self, job: JobId, tid: Tid, request: &ResourceRequirements) -> Result<bool, RequestError> {
+        let claim = self.claims.get(&job).ok_or(RequestError::UnknownJob)?;
+        let mut wanted = claim.allocated.clone();
+        wanted.add(request);
+        if !wanted.fits_within(&self.total_resources) {
+            return Err(RequestError::ExceedsCapacity);
+        }
+        if self.policy == AllocationPolicy::Banker && !wanted.fits_within(&claim.max) {
+            return Err(RequestError::ExceedsClaim);
+        }
+
+        // As in `grant_pending`, a request that fits is granted even while
+        // larger ones wait
+        if self.try_grant(job, request) {
+            return Ok(true);
+        }
+        self.pending.push_back(PendingRequest {
+            job,
+            tid,
+            request: request.clone(),
+        });
+        self.waits_changed = true;
+        Ok(false)
+    }
+
+    /// Returns part of what job `job` was granted while running
+    pub fn release_part(&mut self, job: JobId, amount: &ResourceRequirements) -> Result<(), RequestError> {
+        let claim = self.claims.get_mut(&job).ok_or(RequestError::UnknownJob)?;
+        if !amount.fits_within(&claim.allocated.saturating_sub(&claim.base)) {
+            return Err(RequestError::NotHeld);
+        }
+        let account = self.accounts.get_mut(&claim.account);
+        if !account.is_some_and(|account| account.release(amount)) {
+            return Err(RequestError::NotHeld);
+        }
+        claim.allocated.subtract(amount);
+        self.available_resources.add(amount);
+        Ok(())
+    }
+
+    /// Grants waiting requests that can now be granted, in arrival order,
+    /// skipping those that still don't fit. Returns the threads to wake.
+    pub fn grant_pending(&mut self) -> Vec<Tid> {
+        let mut granted = Vec::new();
+        let mut idx = 0;
+        while idx < self.pending.len() {
+            let job = self.pending[idx].job;
+            let request = self.pending[idx].request.clone();
+            if self.try_grant(job, &request) {
+                granted.push(self.pending.remove(idx).unwrap().tid);
+                self.waits_changed = true;
+            } else {
+                idx += 1;
+            }
+        }
+        granted
+    }
+
+    /// Forgets a waiting thread's request, as when it is killed
+    pub fn cancel_request(&mut self, tid: Tid) {
+        let len = self.pending.len();
+        self.pending.retain(|pending| pending.tid != tid);
+        self.waits_changed |= self.pending.len() != len;
+    }
+
+    fn try_grant(&mut self, job: JobId, request: &ResourceRequirements) -> bool {
+        let Some(claim) = self.claims.get(&job) else {
+            return false;
+        };
+        if !request.fits_within(&self.available_resources)
+            || !self.accounts.get(&claim.account).is_none_or(|a| a.can_allocate(request))
         {
-            self.available_resources.cpu -= requirements.cpu;
-            self.available_resources.memory -= requirements.memory;
-            true
-        } else {
-            false
+            return false;
+        }
+        if self.policy == AllocationPolicy::Banker {
+            let mut available = self.available_resources.clone();
+            available.subtract(request);
+            let mut allocated = claim.allocated.clone();
+            allocated.add(request);
+            let needs = self.claims.iter().map(|(&id, other)| match id == job {
+                true => (claim.max.saturating_sub(&allocated), allocated.clone()),
+                false => (other.max.saturating_sub(&other.allocated), other.allocated.clone()),
+            });
+            if !is_safe(available, needs.collect()) {
+                return false;
+            }
+        }
+
+        let claim = self.claims.get_mut(&job).unwrap();
+        claim.allocated.add(request);
+        let account = claim.account.clone();
+        self.available_resources.subtract(request);
+        self.account_mut(&account).allocate(request);
+        true
+    }
+
+    /// Whether starting `task` leaves the running jobs in a safe state
+    fn safe_to_start(&self, task: &Task) -> bool {
+        let requirements = &task.resource_requirements;
+        let mut available = self.available_resources.clone();
+        available.subtract(requirements);
+        let mut needs: Vec<_> = self
+            .claims
+            .values()
+            .map(|claim| (claim.max.saturating_sub(&claim.allocated), claim.allocated.clone()))
+            .collect();
+        needs.push((task.max_claim().saturating_sub(requirements), requirements.clone()));
+        is_safe(available, needs)
+    }
+
+    /// Looks for deadlocked jobs if waiting requests have changed since the
+    /// last look, returning them if there are any. Only in `Detect` mode.
+    pub fn detect_deadlock(&mut self) -> Option<Vec<JobId>> {
+        if self.policy != AllocationPolicy::Detect || !core::mem::replace(&mut self.waits_changed, false) {
+            return None;
+        }
+        self.deadlocked = self.find_deadlocked_jobs();
+        (!self.deadlocked.is_empty()).then(|| self.deadlocked.clone())
+    }
+
+    /// Jobs the last detection pass found deadlocked
+    pub fn get_deadlocked_jobs(&self) -> &[JobId] {
+        &self.deadlocked
+    }
+
+    /// Jobs whose waiting requests can never be granted: optimistically lets
+    /// every job that isn't waiting, then every job whose requests fit in
+    /// what is free, finish and return what it holds, until no more can.
+    /// Whoever is left waits, directly or through a chain, on resources held
+    /// only by others who are left.
+    fn find_deadlocked_jobs(&self) -> Vec<JobId> {
+        let mut waiting: BTreeMap<JobId, ResourceRequirements> = BTreeMap::new();
+        for pending in &self.pending {
+            waiting
+                .entry(pending.job)
+                .or_insert_with(|| ResourceRequirements::new(0, 0))
+                .add(&pending.request);
+        }
+        let mut work = self.available_resources.clone();
+        for (job, claim) in &self.claims {
+            if !waiting.contains_key(job) {
+                work.add(&claim.allocated);
+            }
+        }
+
+        let mut finished = true;
+        while finished {
+            finished = false;
+            waiting.retain(|job, request| {
+                if !request.fits_within(&work) {
+                    return true;
+                }
+                if let Some(claim) = self.claims.get(job) {
+                    work.add(&claim.allocated);
+                }
+                finished = true;
+                false
+            });
+        }
+        waiting.into_keys().collect()
+    }
+
+    /// What each waiting request of `job` asks for, with the waiting thread
+    pub fn pending_requests(&self, job: JobId) -> impl Iterator<Item = (Tid, &ResourceRequirements)> {
+        self.pending.iter().filter(move |p| p.job == job).map(|p| (p.tid, &p.request))
+    }
+
+    /// What running job `job` holds
+    pub fn allocated_to(&self, job: JobId) -> Option<&ResourceRequirements> {
+        self.claims.get(&job).map(|claim| &claim.allocated)
+    }
+
+    pub fn can_allocate(&self, task: &Task, window: Range<u64>) -> bool {
+        let requirements = &task.resource_requirements;
+        if let Some(name) = &task.reservation {
+            // Jobs in a reservation run only inside its window and capacity
+            match self.reservations.iter().find(|r| &r.name == name) {
+                Some(r) if r.owner == task.account && r.is_active(window.start) => {
+                    let mut held = r.in_use.clone();
+                    held.add(requirements);
+                    if !held.fits_within(&r.resources) {
+                        return false;
+                    }
+                }
+                _ => return false,
+            }
         }
+
+        let mut needed = self.reserved_for_others(task, &window);
+        needed.add(requirements);
+        needed.fits_within(&self.available_resources)
+            && self.accounts.get(&task.account).is_none_or(|a| a.can_allocate(requirements))
+            && (self.policy != AllocationPolicy::Banker || self.safe_to_start(task))
+    }
+
+    /// Whether the task could ever be placed on this machine, under its
+    /// account's quota and, if it names one, inside its reservation
+    pub fn can_ever_allocate(&self, task: &Task, now: u64) -> bool {
+        let requirements = &task.resource_requirements;
+        let in_reservation = match &task.reservation {
+            None => true,
+            Some(name) => self.reservations.iter().any(|r| {
+                &r.name == name
+                    && r.owner == task.account
+                    && !r.has_ended(now)
+                    && requirements.fits_within(&r.resources)
+            }),
+        };
+        let max_fits = self.policy != AllocationPolicy::Banker || task.max_claim().fits_within(&self.total_resources);
+        in_reservation
+            && max_fits
+            && requirements.fits_within(&self.total_resources)
+            && self.get_quota(&task.account).permits(requirements.cpu, requirements.memory)
     }
 
-    pub fn release_resources(&mut self, requirements: &ResourceRequirements) {
-        self.available_resources.cpu += requirements.cpu;
-        self.available_resources.memory += requirements.memory;
+    /// Charges an account for CPU-ticks its job consumed
+    pub fn record_usage(&mut self, account: &str, cpu_ticks: u64) {
+        let now = timer::ticks();
+        let half_life = self.fair_share_policy.half_life;
+        self.account_mut(account).record_usage(cpu_ticks, now, half_life);
+    }
+
+    pub fn fair_share_factors(&mut self) -> FairShareFactors {
+        FairShareFactors::compute(&mut self.accounts, &self.fair_share_policy, timer::ticks())
     }
 
     pub fn get_available_resources(&self) -> &ResourceRequirements {
         &self.available_resources
     }
 }
+
+/// The banker's safety check: whether, given `needs` (what each job may
+/// still ask for, and what it holds), the jobs can run to completion in some
+/// order, each returning what it holds once its whole need has been met
+fn is_safe(mut available: ResourceRequirements, mut needs: Vec<(ResourceRequirements, ResourceRequirements)>) -> bool {
+    while !needs.is_empty() {
+        let Some(idx) = needs.iter().position(|(need, _)| need.fits_within(&available)) else {
+            return false;
+        };
+        let (_, allocated) = needs.swap_remove(idx);
+        available.add(&allocated);
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::task::{CpuSet, RetryPolicy, TaskLimits, TaskStatus};
+
+    fn task(account: &str, reservation: Option<&str>, cpu: u32, memory: u64) -> Task {
+        Task {
+            executable: String::from("compute"),
+            arguments: Vec::new(),
+            account: String::from(account),
+            priority: 1,
+            resource_requirements: ResourceRequirements::new(cpu, memory),
+            max_resources: None,
+            estimated_runtime: 0,
+            dependencies: Vec::new(),
+            limits: TaskLimits::default(),
+            retry: RetryPolicy::default(),
+            reservation: reservation.map(String::from),
+            affinity: CpuSet::all(),
+            status: TaskStatus::Pending,
+        }
+    }
+
+    #[test_case]
+    fn reservation_job_releases_resources_granted_while_running() {
+        let total = ResourceRequirements::new(4, 1024);
+        let mut manager = ResourceManager::new(total.clone());
+        assert!(manager.create_reservation(AdvanceReservation::new(
+            "nightly", "ops", ResourceRequirements::new(2, 256), 0, 1000,
+        )));
+        let job = task("ops", Some("nightly"), 2, 256);
+        assert!(manager.allocate_resources(JobId(1), &job, 0..10));
+
+        // Granted beyond what the job started with, so outside the reservation
+        assert_eq!(manager.request_resources(JobId(1), Tid(1), &ResourceRequirements::new(1, 128)), Ok(true));
+        let in_use = &manager.get_reservations()[0].in_use;
+        assert_eq!((in_use.cpu, in_use.memory), (2, 256));
+
+        assert!(manager.release_resources(JobId(1), &job));
+        let available = manager.get_available_resources();
+        assert_eq!((available.cpu, available.memory), (total.cpu, total.memory));
+        let in_use = &manager.get_reservations()[0].in_use;
+        assert_eq!((in_use.cpu, in_use.memory), (0, 0));
+        assert!(manager.allocated_to(JobId(1)).is_none());
+    }
+
+    #[test_case]
+    fn request_that_fits_is_granted_while_a_larger_one_waits() {
+        let mut manager = ResourceManager::new(ResourceRequirements::new(4, 1024));
+        assert!(manager.allocate_resources(JobId(1), &task("a", None, 1, 256), 0..10));
+        assert!(manager.allocate_resources(JobId(2), &task("b", None, 1, 256), 0..10));
+
+        assert_eq!(manager.request_resources(JobId(1), Tid(1), &ResourceRequirements::new(3, 0)), Ok(false));
+        assert_eq!(manager.request_resources(JobId(2), Tid(2), &ResourceRequirements::new(1, 0)), Ok(true));
+        assert!(manager.grant_pending().is_empty());
+
+        manager.release_part(JobId(2), &ResourceRequirements::new(1, 0)).unwrap();
+        assert!(manager.release_resources(JobId(2), &task("b", None, 1, 256)));
+        assert_eq!(manager.grant_pending(), [Tid(1)]);
+    }
+}
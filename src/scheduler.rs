@@ -1,69 +1,343 @@
 use alloc::collections::VecDeque;
-use crate::task::{Task, ResourceRequirements, TaskStatus};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::Range;
+use crate::account::FairShareFactors;
+use crate::irq_lock::SpinMutex;
+use crate::resource_manager::ResourceManager;
+use crate::task::{JobId, Task, ResourceRequirements, TaskStatus};
+use crate::timer;
+use crate::println;
+
+/// Raises the effective priority of queued tasks the longer they wait,
+/// so low-priority tasks can't be starved by a stream of high-priority ones.
+#[derive(Debug, Clone)]
+pub struct AgingPolicy {
+    /// Ticks a task must wait for each priority boost (0 disables aging)
+    pub interval: u64,
+    /// Priority added per elapsed interval
+    pub boost: u32,
+    /// Effective priority never ages past this value
+    pub max_priority: u32,
+}
+
+impl AgingPolicy {
+    pub const fn disabled() -> Self {
+        AgingPolicy {
+            interval: 0,
+            boost: 0,
+            max_priority: 0,
+        }
+    }
+
+    pub fn effective_priority(&self, base_priority: u32, wait_ticks: u64) -> u32 {
+        if self.interval == 0 || base_priority >= self.max_priority {
+            return base_priority;
+        }
+
+        let steps = wait_ticks / self.interval;
+        let aged = base_priority as u64 + steps.saturating_mul(self.boost as u64);
+        aged.min(self.max_priority as u64) as u32
+    }
+}
+
+/// Aging is off unless enabled with `BatchSystem::set_aging_policy`
+impl Default for AgingPolicy {
+    fn default() -> Self {
+        AgingPolicy::disabled()
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct QueuedTask {
+    pub id: JobId,
+    pub task: Task,
+    pub enqueued_at: u64,
+    /// Held tasks stay queued but are never picked to run
+    pub held: bool,
+    /// Tick before which the task may not start, e.g. while backing off a retry
+    pub not_before: u64,
+}
+
+impl QueuedTask {
+    pub fn wait_ticks(&self, now: u64) -> u64 {
+        now.saturating_sub(self.enqueued_at)
+    }
+}
+
+/// A task that currently holds resources, as seen by the backfill planner
+#[derive(Debug, Clone)]
+pub struct RunningTask {
+    pub resource_requirements: ResourceRequirements,
+    /// Tick at which the task is expected to finish (None if its runtime is unknown)
+    pub expected_end: Option<u64>,
+}
+
+impl RunningTask {
+    pub fn new(task: &Task, started_at: u64) -> Self {
+        RunningTask {
+            resource_requirements: task.resource_requirements.clone(),
+            expected_end: expected_end(task, started_at),
+        }
+    }
+}
+
+/// Falls back to the wall-time limit when no runtime was declared, since the
+/// task is killed by then anyway
+fn expected_end(task: &Task, start: u64) -> Option<u64> {
+    let runtime = match task.estimated_runtime {
+        0 => task.limits.wall_time?,
+        runtime => runtime,
+    };
+    Some(start.saturating_add(runtime))
+}
+
+/// Ticks a task started at `start` is expected to hold its resources for
+fn run_window(task: &Task, start: u64) -> Range<u64> {
+    start..expected_end(task, start).unwrap_or(u64::MAX)
+}
+
+/// Start time promised to the highest-priority task that is blocked on resources
+#[derive(Debug, Clone)]
+pub struct Reservation {
+    pub executable: String,
+    pub start_tick: u64,
+}
 
 pub struct Scheduler {
-    pub task_queue: VecDeque<Task>,
-    available_resources: ResourceRequirements,
+    pub task_queue: VecDeque<QueuedTask>,
+    resource_manager: Arc<SpinMutex<ResourceManager>>,
+    aging_policy: AgingPolicy,
+    /// Per-account bonuses as of the last scheduling pass
+    fair_share: FairShareFactors,
+    reservation: Option<Reservation>,
 }
 
 impl Scheduler {
-    pub fn new(available_resources: ResourceRequirements) -> Self {
+    pub fn new(resource_manager: Arc<SpinMutex<ResourceManager>>) -> Self {
         Scheduler {
             task_queue: VecDeque::new(),
-            available_resources,
+            resource_manager,
+            aging_policy: AgingPolicy::default(),
+            fair_share: FairShareFactors::default(),
+            reservation: None,
+        }
+    }
+
+    pub fn set_aging_policy(&mut self, aging_policy: AgingPolicy) {
+        self.aging_policy = aging_policy;
+    }
+
+    pub fn get_aging_policy(&self) -> &AgingPolicy {
+        &self.aging_policy
+    }
+
+    pub fn add_task(&mut self, id: JobId, task: Task) {
+        self.add_task_after(id, task, 0);
+    }
+
+    /// Queues a task that may not start before tick `not_before`
+    pub fn add_task_after(&mut self, id: JobId, mut task: Task, not_before: u64) {
+        task.status = TaskStatus::Pending;
+        self.task_queue.push_back(QueuedTask {
+            id,
+            task,
+            enqueued_at: timer::ticks(),
+            held: false,
+            not_before,
+        });
+    }
+
+    /// Earliest tick at which a queued task may become runnable without
+    /// anything else finishing: a retry stops backing off, or an advance
+    /// reservation starts or ends
+    pub fn next_wakeup(&self, now: u64) -> Option<u64> {
+        let backoff = self
+            .task_queue
+            .iter()
+            .filter(|queued| !queued.held && queued.not_before > now)
+            .map(|queued| queued.not_before)
+            .min();
+        let boundary = match self.task_queue.iter().any(|queued| !queued.held) {
+            true => self.resource_manager.lock().next_reservation_boundary(now),
+            false => None,
+        };
+        backoff.into_iter().chain(boundary).min()
+    }
+
+    /// Removes a queued task, e.g. when it is cancelled
+    pub fn remove_task(&mut self, id: JobId) -> Option<Task> {
+        let idx = self.task_queue.iter().position(|queued| queued.id == id)?;
+        self.task_queue.remove(idx).map(|queued| queued.task)
+    }
+
+    /// Holds or releases a queued task, returning false if it isn't queued
+    pub fn set_held(&mut self, id: JobId, held: bool) -> bool {
+        match self.task_queue.iter_mut().find(|queued| queued.id == id) {
+            Some(queued) => {
+                queued.held = held;
+                true
+            }
+            None => false,
         }
     }
 
-    pub fn add_task(&mut self, mut task: Task) {
-        task.status = TaskStatus::Queued;
-        self.task_queue.push_back(task);
+    pub fn is_held(&self, id: JobId) -> bool {
+        self.task_queue.iter().any(|queued| queued.id == id && queued.held)
+    }
+
+    /// Aged priority plus the fair-share bonus of the task's account
+    pub fn effective_priority(&self, queued: &QueuedTask, now: u64) -> u32 {
+        self.aging_policy
+            .effective_priority(queued.task.priority, queued.wait_ticks(now))
+            + self.fair_share.bonus(&queued.task.account)
+    }
+
+    /// Indices of runnable (not held or backing off) queued tasks ordered by
+    /// effective priority, earliest queued first on ties
+    fn priority_order(&self, now: u64) -> Vec<usize> {
+        let mut order: Vec<usize> = (0..self.task_queue.len())
+            .filter(|&idx| !self.task_queue[idx].held && self.task_queue[idx].not_before <= now)
+            .collect();
+        order.sort_by_key(|&idx| core::cmp::Reverse(self.effective_priority(&self.task_queue[idx], now)));
+        order
     }
 
-    pub fn schedule_next_task(&mut self) -> Option<Task> {
-        // Find the highest priority task that can be executed
-        let mut highest_priority_idx = None;
-        let mut highest_priority = 0;
+    /// Picks the next task to run using EASY backfilling and allocates its
+    /// resources. The highest-priority task runs if it fits; otherwise it gets
+    /// a reservation at the earliest tick enough running tasks will have
+    /// finished, and lower-priority tasks may only start if they cannot delay
+    /// that reservation.
+    pub fn schedule_next_task(&mut self, running: &[RunningTask]) -> Option<(JobId, Task)> {
+        let now = timer::ticks();
+        let mut resource_manager = self.resource_manager.lock();
+        self.fair_share = resource_manager.fair_share_factors();
+        let available = resource_manager.get_available_resources().clone();
+
+        let order = self.priority_order(now);
+        let (&head_idx, rest) = order.split_first()?;
 
-        for (idx, task) in self.task_queue.iter().enumerate() {
-            if task.priority > highest_priority && self.can_execute_task(task) {
-                highest_priority = task.priority;
-                highest_priority_idx = Some(idx);
+        let head = &self.task_queue[head_idx].task;
+        if resource_manager.allocate_resources(self.task_queue[head_idx].id, head, run_window(head, now)) {
+            drop(resource_manager);
+            self.reservation = None;
+            return Some(self.start_task(head_idx));
+        }
+
+        // Walk running tasks in completion order until the head task fits
+        let mut ending: Vec<&RunningTask> = running.iter().collect();
+        ending.sort_by_key(|r| r.expected_end.unwrap_or(u64::MAX));
+        let mut free_at_shadow = available.clone();
+        let mut shadow_time = None;
+        for task in ending {
+            let Some(end) = task.expected_end else { break };
+            free_at_shadow.add(&task.resource_requirements);
+            if head.resource_requirements.fits_within(&free_at_shadow) {
+                shadow_time = Some(end);
+                break;
             }
         }
 
-        if let Some(idx) = highest_priority_idx {
-            let mut task = self.task_queue.remove(idx).unwrap();
-            task.status = TaskStatus::Running;
-            self.update_available_resources(&task);
-            Some(task)
-        } else {
-            None
+        // Resources still spare once the head task has started at the shadow time
+        let mut extra = free_at_shadow.clone();
+        if shadow_time.is_some() {
+            extra.subtract(&head.resource_requirements);
+        }
+        // With no known end to wait for, the head has no reserved start, so
+        // only backfill what leaves room for it beside everything running
+        let mut committed = head.resource_requirements.clone();
+        for task in running {
+            committed.add(&task.resource_requirements);
         }
+        let beside_head = resource_manager.total_resources.saturating_sub(&committed);
+
+        self.reservation = shadow_time.map(|start_tick| Reservation {
+            executable: head.executable.clone(),
+            start_tick,
+        });
+
+        for &idx in rest {
+            let task = &self.task_queue[idx].task;
+            if !resource_manager.can_allocate(task, run_window(task, now)) {
+                continue;
+            }
+
+            let Some(shadow) = shadow_time else {
+                if !task.resource_requirements.fits_within(&beside_head) {
+                    continue;
+                }
+                resource_manager.allocate_resources(self.task_queue[idx].id, task, run_window(task, now));
+                drop(resource_manager);
+                return Some(self.start_task(idx));
+            };
+
+            let ends_before_shadow = expected_end(task, now).is_some_and(|end| end <= shadow);
+            let fits_in_extra = task.resource_requirements.fits_within(&extra);
+            if ends_before_shadow || fits_in_extra {
+                resource_manager.allocate_resources(self.task_queue[idx].id, task, run_window(task, now));
+                drop(resource_manager);
+                println!("[SCHED] Backfilling {} ahead of {} (reserved for tick {})",
+                    task.executable, head.executable, shadow);
+                return Some(self.start_task(idx));
+            }
+        }
+
+        None
+    }
+
+    fn start_task(&mut self, idx: usize) -> (JobId, Task) {
+        let QueuedTask { id, mut task, .. } = self.task_queue.remove(idx).unwrap();
+        task.status = TaskStatus::Running;
+        (id, task)
     }
 
-    fn can_execute_task(&self, task: &Task) -> bool {
-        task.resource_requirements.cpu <= self.available_resources.cpu
-            && task.resource_requirements.memory <= self.available_resources.memory
+    /// Removes queued tasks that need more resources than the machine has in
+    /// total, more than their account's quota allows, or a reservation that
+    /// can't take them
+    pub fn take_unsatisfiable_tasks(&mut self) -> Vec<(JobId, Task)> {
+        let now = timer::ticks();
+        let resource_manager = self.resource_manager.lock();
+        let mut unsatisfiable = Vec::new();
+        let mut idx = 0;
+        while idx < self.task_queue.len() {
+            let task = &self.task_queue[idx].task;
+            if resource_manager.can_ever_allocate(task, now) {
+                idx += 1;
+            } else {
+                let queued = self.task_queue.remove(idx).unwrap();
+                unsatisfiable.push((queued.id, queued.task));
+            }
+        }
+        unsatisfiable
     }
 
-    fn update_available_resources(&mut self, task: &Task) {
-        self.available_resources.cpu -= task.resource_requirements.cpu;
-        self.available_resources.memory -= task.resource_requirements.memory;
+    pub fn get_reservation(&self) -> Option<&Reservation> {
+        self.reservation.as_ref()
     }
 
     pub fn get_queue_length(&self) -> usize {
         self.task_queue.len()
     }
 
+    pub fn get_queue_length_for(&self, account: &str) -> usize {
+        self.task_queue.iter().filter(|queued| queued.task.account == account).count()
+    }
+
     pub fn get_last_task_priority(&self) -> u32 {
-        self.task_queue.back().map(|t| t.priority).unwrap_or(0)
+        self.task_queue.back().map(|q| q.task.priority).unwrap_or(0)
     }
 
     pub fn get_next_task_priority(&self) -> Option<u32> {
-        self.task_queue.front().map(|t| t.priority)
+        let now = timer::ticks();
+        self.task_queue
+            .iter()
+            .filter(|queued| !queued.held)
+            .map(|queued| self.effective_priority(queued, now))
+            .max()
     }
 
     pub fn get_last_task(&self) -> Option<&Task> {
-        self.task_queue.back()
+        self.task_queue.back().map(|q| &q.task)
     }
 }
@@ -0,0 +1,80 @@
+use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use riscv::register::mhartid;
+use spin::Once;
+use crate::batch_system::BatchSystem;
+use crate::percpu;
+use crate::timer;
+use crate::println;
+
+/// Harts the kernel has boot stacks for; must match `MAX_HARTS` in boot.S
+pub const MAX_HARTS: usize = 4;
+
+/// Ticks the boot hart waits for secondary harts to come online
+const BRING_UP_TIMEOUT: u64 = timer::TICKS_PER_SECOND / 2;
+
+/// Secondary harts spin in boot.S until this is set, then enter `secondary_main`
+#[no_mangle]
+static SECONDARY_RELEASE: AtomicU32 = AtomicU32::new(0);
+
+static BOOT_HART: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// Batch system the idle loops take work from
+static BATCH_SYSTEM: Once<&'static BatchSystem> = Once::new();
+
+pub fn hart_id() -> usize {
+    mhartid::read()
+}
+
+/// Records the calling hart as the one that booted the kernel
+pub fn init_boot_hart() {
+    let id = hart_id();
+    percpu::init(id);
+    BOOT_HART.store(id, Ordering::SeqCst);
+    percpu::cpu(id).online.store(true, Ordering::SeqCst);
+}
+
+/// The boot hart keeps time for the whole machine
+pub fn is_boot_hart() -> bool {
+    BOOT_HART.load(Ordering::Relaxed) == hart_id()
+}
+
+pub fn online_harts() -> usize {
+    percpu::all().filter(|cpu| cpu.online.load(Ordering::SeqCst)).count()
+}
+
+/// Releases the parked secondary harts, which then run jobs from
+/// `batch_system`, and waits briefly for them to come online. Returns how
+/// many harts are online.
+pub fn start_secondary_harts(batch_system: &'static BatchSystem) -> usize {
+    BATCH_SYSTEM.call_once(|| batch_system);
+    SECONDARY_RELEASE.store(1, Ordering::Release);
+
+    let deadline = timer::ticks() + BRING_UP_TIMEOUT;
+    while online_harts() < MAX_HARTS && timer::ticks() < deadline {
+        core::hint::spin_loop();
+    }
+    online_harts()
+}
+
+/// Entry point of secondary harts once they are released from boot.S
+#[no_mangle]
+pub extern "C" fn secondary_main() -> ! {
+    let id = hart_id();
+    percpu::init(id);
+    crate::interrupts::init();
+    timer::init_hart();
+    percpu::cpu(id).online.store(true, Ordering::SeqCst);
+    println!("[SMP] Hart {} online", id);
+    idle_loop()
+}
+
+/// Runs time slices of whatever jobs are waiting for a CPU, sleeping until
+/// the next interrupt when there are none
+pub fn idle_loop() -> ! {
+    loop {
+        let ran = BATCH_SYSTEM.get().is_some_and(|batch_system| batch_system.run_on_hart());
+        if !ran {
+            unsafe { riscv::asm::wfi() };
+        }
+    }
+}
@@ -0,0 +1,549 @@
+//! Blocking synchronization primitives shared between jobs and their threads.
+//!
+//! Unlike the spinlocks in `irq_lock`, a thread that can't take one of these
+//! is parked: it leaves its hart's run queue until another thread wakes it.
+//! Wake-ups hand the primitive straight to the woken thread (the mutex is
+//! already its own, the semaphore unit already taken), so a thread never
+//! retries an operation after blocking on it.
+//!
+//! Objects are named by a key chosen by the programs; threads that create one
+//! with the same key share it, whether or not they belong to the same job.
+
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use crate::irq_lock::SpinMutex;
+use crate::task::Tid;
+
+/// Something a parked thread is waiting for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitChannel {
+    Mutex(usize),
+    Semaphore(usize),
+    Condvar(usize),
+    /// A futex, by the address of its word
+    Futex(usize),
+    /// Resources requested from the resource manager
+    Resources,
+    /// A thread of the same job, to exit
+    Thread(Tid),
+}
+
+/// Threads waiting on one primitive, woken in arrival order
+#[derive(Debug, Default)]
+pub struct WaitQueue {
+    waiters: VecDeque<Tid>,
+}
+
+impl WaitQueue {
+    pub fn push(&mut self, thread: Tid) {
+        self.waiters.push_back(thread);
+    }
+
+    pub fn pop(&mut self) -> Option<Tid> {
+        self.waiters.pop_front()
+    }
+
+    /// Removes `thread` if it is waiting, returning whether it was
+    pub fn remove(&mut self, thread: Tid) -> bool {
+        let len = self.waiters.len();
+        self.waiters.retain(|&waiter| waiter != thread);
+        self.waiters.len() != len
+    }
+
+    pub fn len(&self) -> usize {
+        self.waiters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiters.is_empty()
+    }
+}
+
+/// A mutex that parks threads waiting for it rather than spinning
+#[derive(Debug, Default)]
+pub struct SleepMutex {
+    owner: Option<Tid>,
+    waiters: WaitQueue,
+}
+
+impl SleepMutex {
+    /// Takes the mutex for `thread`, returning false if it must wait. The
+    /// mutex isn't recursive: an owner locking it again gets an error rather
+    /// than waiting on itself forever.
+    pub fn lock(&mut self, thread: Tid) -> Result<bool, SyncError> {
+        match self.owner {
+            None => {
+                self.owner = Some(thread);
+                Ok(true)
+            }
+            Some(owner) if owner == thread => Err(SyncError::AlreadyOwner),
+            Some(_) => {
+                self.waiters.push(thread);
+                Ok(false)
+            }
+        }
+    }
+
+    /// Releases the mutex, handing it to the first waiter. Returns the thread
+    /// to wake.
+    pub fn unlock(&mut self, thread: Tid) -> Result<Option<Tid>, SyncError> {
+        if self.owner != Some(thread) {
+            return Err(SyncError::NotOwner);
+        }
+        self.owner = self.waiters.pop();
+        Ok(self.owner)
+    }
+
+    pub fn owner(&self) -> Option<Tid> {
+        self.owner
+    }
+}
+
+/// A counting semaphore that parks threads waiting for a unit
+#[derive(Debug, Default)]
+pub struct Semaphore {
+    count: usize,
+    waiters: WaitQueue,
+}
+
+impl Semaphore {
+    pub fn new(count: usize) -> Self {
+        Semaphore {
+            count,
+            waiters: WaitQueue::default(),
+        }
+    }
+
+    /// Takes a unit for `thread`, returning false if it must wait
+    pub fn down(&mut self, thread: Tid) -> bool {
+        if self.count > 0 {
+            self.count -= 1;
+            return true;
+        }
+        self.waiters.push(thread);
+        false
+    }
+
+    /// Returns a unit, handing it to the first waiter if there is one.
+    /// Returns the thread to wake.
+    pub fn up(&mut self) -> Option<Tid> {
+        let waiter = self.waiters.pop();
+        if waiter.is_none() {
+            self.count += 1;
+        }
+        waiter
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+/// A condition variable. Waiters give up a `SleepMutex` while they wait and
+/// own it again when they wake.
+#[derive(Debug, Default)]
+pub struct Condvar {
+    /// Each waiter and the mutex it must reacquire
+    waiters: VecDeque<(Tid, usize)>,
+}
+
+/// Every primitive, by kind and key
+#[derive(Debug, Default)]
+pub struct SyncTable {
+    mutexes: BTreeMap<usize, SleepMutex>,
+    semaphores: BTreeMap<usize, Semaphore>,
+    condvars: BTreeMap<usize, Condvar>,
+}
+
+/// Result of an operation that may block the calling thread or wake others
+#[derive(Debug, Default)]
+pub struct SyncEffect {
+    /// Set if the calling thread must park until woken
+    pub blocked_on: Option<WaitChannel>,
+    pub wake: Vec<Tid>,
+}
+
+/// Why a sync operation failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncError {
+    /// No object was created with that key
+    NotFound,
+    /// The thread unlocked a mutex it doesn't own
+    NotOwner,
+    /// The thread locked a mutex it already owns
+    AlreadyOwner,
+}
+
+impl SyncEffect {
+    fn blocked(channel: WaitChannel) -> Self {
+        SyncEffect {
+            blocked_on: Some(channel),
+            wake: Vec::new(),
+        }
+    }
+
+    fn wake(thread: Option<Tid>) -> Self {
+        SyncEffect {
+            blocked_on: None,
+            wake: thread.into_iter().collect(),
+        }
+    }
+}
+
+impl SyncTable {
+    pub const fn new() -> Self {
+        SyncTable {
+            mutexes: BTreeMap::new(),
+            semaphores: BTreeMap::new(),
+            condvars: BTreeMap::new(),
+        }
+    }
+
+    pub fn create_mutex(&mut self, key: usize) {
+        self.mutexes.entry(key).or_default();
+    }
+
+    /// Creates the semaphore with `count` units, unless it already exists
+    pub fn create_semaphore(&mut self, key: usize, count: usize) {
+        self.semaphores.entry(key).or_insert_with(|| Semaphore::new(count));
+    }
+
+    pub fn create_condvar(&mut self, key: usize) {
+        self.condvars.entry(key).or_default();
+    }
+
+    pub fn mutex_lock(&mut self, key: usize, thread: Tid) -> Result<SyncEffect, SyncError> {
+        let mutex = self.mutexes.get_mut(&key).ok_or(SyncError::NotFound)?;
+        Ok(match mutex.lock(thread)? {
+            true => SyncEffect::default(),
+            false => SyncEffect::blocked(WaitChannel::Mutex(key)),
+        })
+    }
+
+    pub fn mutex_unlock(&mut self, key: usize, thread: Tid) -> Result<SyncEffect, SyncError> {
+        let mutex = self.mutexes.get_mut(&key).ok_or(SyncError::NotFound)?;
+        let next = mutex.unlock(thread)?;
+        Ok(SyncEffect::wake(next))
+    }
+
+    pub fn semaphore_down(&mut self, key: usize, thread: Tid) -> Result<SyncEffect, SyncError> {
+        let semaphore = self.semaphores.get_mut(&key).ok_or(SyncError::NotFound)?;
+        Ok(match semaphore.down(thread) {
+            true => SyncEffect::default(),
+            false => SyncEffect::blocked(WaitChannel::Semaphore(key)),
+        })
+    }
+
+    pub fn semaphore_up(&mut self, key: usize) -> Result<SyncEffect, SyncError> {
+        let semaphore = self.semaphores.get_mut(&key).ok_or(SyncError::NotFound)?;
+        Ok(SyncEffect::wake(semaphore.up()))
+    }
+
+    /// Releases `mutex` and parks `thread` on the condition variable
+    pub fn condvar_wait(&mut self, key: usize, mutex: usize, thread: Tid) -> Result<SyncEffect, SyncError> {
+        if !self.condvars.contains_key(&key) {
+            return Err(SyncError::NotFound);
+        }
+        let mut effect = self.mutex_unlock(mutex, thread)?;
+        self.condvars.get_mut(&key).unwrap().waiters.push_back((thread, mutex));
+        effect.blocked_on = Some(WaitChannel::Condvar(key));
+        Ok(effect)
+    }
+
+    /// Wakes the first waiter, which must first get its mutex back: it is
+    /// woken now if the mutex is free, or later when the mutex is handed to it
+    pub fn condvar_signal(&mut self, key: usize) -> Result<SyncEffect, SyncError> {
+        let condvar = self.condvars.get_mut(&key).ok_or(SyncError::NotFound)?;
+        let Some((thread, mutex)) = condvar.waiters.pop_front() else {
+            return Ok(SyncEffect::default());
+        };
+        let acquired = self.mutexes.get_mut(&mutex).is_some_and(|m| m.lock(thread) == Ok(true));
+        Ok(SyncEffect::wake(acquired.then_some(thread)))
+    }
+
+    /// Drops `thread` from every wait queue and releases the mutexes it holds,
+    /// as when it exits or is killed. Returns the threads this wakes.
+    pub fn release_thread(&mut self, thread: Tid) -> Vec<Tid> {
+        let mut wake = Vec::new();
+        for mutex in self.mutexes.values_mut() {
+            mutex.waiters.remove(thread);
+            if let Ok(Some(next)) = mutex.unlock(thread) {
+                wake.push(next);
+            }
+        }
+        for semaphore in self.semaphores.values_mut() {
+            semaphore.waiters.remove(thread);
+        }
+        for condvar in self.condvars.values_mut() {
+            condvar.waiters.retain(|&(waiter, _)| waiter != thread);
+        }
+        wake
+    }
+}
+
+/// Primitives shared by every job
+pub static SYNC: SpinMutex<SyncTable> = SpinMutex::new(SyncTable::new());
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    const A: Tid = Tid(1);
+    const B: Tid = Tid(2);
+    const C: Tid = Tid(3);
+
+    fn tid(thread: usize) -> Tid {
+        Tid(thread as u64 + 1)
+    }
+
+    /// Steps `threads` threads in turn, each for up to `slice` steps, parking
+    /// and waking them as their sync calls say, until `step` returns None for
+    /// every thread. Returns false if every thread left is parked.
+    fn run_threads(threads: usize, slice: usize, mut step: impl FnMut(usize) -> Option<SyncEffect>) -> bool {
+        let mut parked = vec![false; threads];
+        let mut finished = vec![false; threads];
+        while finished.contains(&false) {
+            let mut ran = false;
+            for thread in 0..threads {
+                for _ in 0..slice {
+                    if parked[thread] || finished[thread] {
+                        break;
+                    }
+                    ran = true;
+                    let Some(effect) = step(thread) else {
+                        finished[thread] = true;
+                        break;
+                    };
+                    parked[thread] = effect.blocked_on.is_some();
+                    for woken in effect.wake {
+                        parked[woken.0 as usize - 1] = false;
+                    }
+                }
+            }
+            if !ran {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Steps of a philosopher at `seat` who picks up the forks (mutexes)
+    /// `first` then `second`, as the `philosopher` program does
+    fn philosopher(table: &mut SyncTable, seat: usize, forks: (usize, usize), phase: &mut usize) -> SyncEffect {
+        let thread = tid(seat);
+        let (first, second) = forks;
+        match *phase {
+            0 => {
+                *phase = 1;
+                table.mutex_lock(first, thread).unwrap()
+            }
+            1 => {
+                *phase = 2;
+                table.mutex_lock(second, thread).unwrap()
+            }
+            _ => {
+                *phase = 0;
+                assert_eq!(table.mutexes[&first].owner(), Some(thread));
+                assert_eq!(table.mutexes[&second].owner(), Some(thread));
+                let mut effect = table.mutex_unlock(second, thread).unwrap();
+                effect.wake.extend(table.mutex_unlock(first, thread).unwrap().wake);
+                effect
+            }
+        }
+    }
+
+    #[test_case]
+    fn mutex_hands_off_to_waiters_in_order() {
+        let mut table = SyncTable::new();
+        table.create_mutex(1);
+        assert_eq!(table.mutex_lock(1, A).unwrap().blocked_on, None);
+        assert_eq!(table.mutex_lock(1, B).unwrap().blocked_on, Some(WaitChannel::Mutex(1)));
+        assert_eq!(table.mutex_lock(1, C).unwrap().blocked_on, Some(WaitChannel::Mutex(1)));
+
+        assert_eq!(table.mutex_unlock(1, B).unwrap_err(), SyncError::NotOwner);
+        assert_eq!(table.mutex_unlock(1, A).unwrap().wake, [B]);
+        assert_eq!(table.mutexes[&1].owner(), Some(B));
+        assert_eq!(table.mutex_unlock(1, B).unwrap().wake, [C]);
+        assert!(table.mutex_unlock(1, C).unwrap().wake.is_empty());
+        assert_eq!(table.mutexes[&1].owner(), None);
+    }
+
+    #[test_case]
+    fn mutex_relock_by_owner_is_an_error() {
+        let mut table = SyncTable::new();
+        table.create_mutex(1);
+        table.mutex_lock(1, A).unwrap();
+        assert_eq!(table.mutex_lock(1, A).unwrap_err(), SyncError::AlreadyOwner);
+        assert!(table.mutexes[&1].waiters.is_empty());
+        assert_eq!(table.mutex_lock(2, A).unwrap_err(), SyncError::NotFound);
+    }
+
+    #[test_case]
+    fn semaphore_counts_units() {
+        let mut table = SyncTable::new();
+        table.create_semaphore(1, 2);
+        // Creating it again keeps the existing count
+        table.create_semaphore(1, 5);
+        assert_eq!(table.semaphore_down(1, A).unwrap().blocked_on, None);
+        assert_eq!(table.semaphore_down(1, B).unwrap().blocked_on, None);
+        assert_eq!(table.semaphores[&1].count(), 0);
+        assert_eq!(table.semaphore_down(1, C).unwrap().blocked_on, Some(WaitChannel::Semaphore(1)));
+
+        // The unit goes straight to the waiter rather than back to the count
+        assert_eq!(table.semaphore_up(1).unwrap().wake, [C]);
+        assert_eq!(table.semaphores[&1].count(), 0);
+        assert!(table.semaphore_up(1).unwrap().wake.is_empty());
+        assert_eq!(table.semaphores[&1].count(), 1);
+    }
+
+    #[test_case]
+    fn condvar_waiter_reacquires_mutex() {
+        let mut table = SyncTable::new();
+        table.create_mutex(1);
+        table.create_condvar(2);
+        table.mutex_lock(1, A).unwrap();
+
+        let effect = table.condvar_wait(2, 1, A).unwrap();
+        assert_eq!(effect.blocked_on, Some(WaitChannel::Condvar(2)));
+        assert_eq!(table.mutexes[&1].owner(), None);
+
+        // The signaller holds the mutex, so the waiter is only woken once it is handed over
+        table.mutex_lock(1, B).unwrap();
+        assert!(table.condvar_signal(2).unwrap().wake.is_empty());
+        assert_eq!(table.mutex_unlock(1, B).unwrap().wake, [A]);
+        assert_eq!(table.mutexes[&1].owner(), Some(A));
+    }
+
+    #[test_case]
+    fn condvar_signal_wakes_waiter_when_mutex_is_free() {
+        let mut table = SyncTable::new();
+        table.create_mutex(1);
+        table.create_condvar(2);
+        table.mutex_lock(1, A).unwrap();
+        table.condvar_wait(2, 1, A).unwrap();
+
+        assert_eq!(table.condvar_signal(2).unwrap().wake, [A]);
+        assert_eq!(table.mutexes[&1].owner(), Some(A));
+        assert!(table.condvar_signal(2).unwrap().wake.is_empty());
+        assert_eq!(table.condvar_wait(2, 1, B).unwrap_err(), SyncError::NotOwner);
+    }
+
+    #[test_case]
+    fn release_thread_frees_mutexes_and_leaves_queues() {
+        let mut table = SyncTable::new();
+        table.create_mutex(1);
+        table.create_mutex(2);
+        table.create_semaphore(3, 0);
+        table.create_condvar(4);
+        table.mutex_lock(1, A).unwrap();
+        table.mutex_lock(1, B).unwrap();
+        table.mutex_lock(2, C).unwrap();
+        table.mutex_lock(2, A).unwrap();
+        table.semaphore_down(3, A).unwrap();
+
+        assert_eq!(table.release_thread(A), [B]);
+        assert_eq!(table.mutexes[&1].owner(), Some(B));
+        // A no longer waits for mutex 2 or the semaphore
+        assert!(table.mutex_unlock(2, C).unwrap().wake.is_empty());
+        assert!(table.semaphore_up(3).unwrap().wake.is_empty());
+        assert_eq!(table.semaphores[&3].count(), 1);
+
+        table.mutex_lock(2, C).unwrap();
+        table.condvar_wait(4, 2, C).unwrap();
+        assert!(table.release_thread(C).is_empty());
+        assert!(table.condvar_signal(4).unwrap().wake.is_empty());
+    }
+
+    #[test_case]
+    fn producer_and_consumer_pass_every_item_in_order() {
+        const FREE: usize = 1;
+        const FILLED: usize = 2;
+        const CAPACITY: usize = 2;
+        const ITEMS: usize = 7;
+        let mut table = SyncTable::new();
+        table.create_semaphore(FREE, CAPACITY);
+        table.create_semaphore(FILLED, 0);
+
+        let mut buffer = VecDeque::new();
+        let mut fullest = 0;
+        let (mut produced, mut consumed) = (0, Vec::new());
+        let mut phase = [0; 2];
+        // Long slices let the producer fill the buffer and the consumer empty it
+        let finished = run_threads(2, 5, |thread| {
+            let done = if thread == 0 { produced } else { consumed.len() };
+            if done == ITEMS {
+                return None;
+            }
+            let (down, up) = if thread == 0 { (FREE, FILLED) } else { (FILLED, FREE) };
+            if phase[thread] == 0 {
+                phase[thread] = 1;
+                return Some(table.semaphore_down(down, tid(thread)).unwrap());
+            }
+            phase[thread] = 0;
+            if thread == 0 {
+                buffer.push_back(produced);
+                produced += 1;
+                fullest = fullest.max(buffer.len());
+            } else {
+                consumed.push(buffer.pop_front().unwrap());
+            }
+            Some(table.semaphore_up(up).unwrap())
+        });
+
+        assert!(finished);
+        assert_eq!(consumed, (0..ITEMS).collect::<Vec<_>>());
+        // The buffer filled up, so the producer waited for a free slot
+        assert_eq!(fullest, CAPACITY);
+        assert_eq!(table.semaphores[&FREE].count(), CAPACITY);
+        assert_eq!(table.semaphores[&FILLED].count(), 0);
+    }
+
+    #[test_case]
+    fn philosophers_taking_lower_fork_first_all_eat() {
+        const SEATS: usize = 5;
+        const MEALS: usize = 3;
+        let mut table = SyncTable::new();
+        for fork in 0..SEATS {
+            table.create_mutex(fork);
+        }
+
+        let mut phase = [0; SEATS];
+        let mut meals = [0; SEATS];
+        let finished = run_threads(SEATS, 1, |seat| {
+            if meals[seat] == MEALS {
+                return None;
+            }
+            let (left, right) = (seat, (seat + 1) % SEATS);
+            if phase[seat] == 2 {
+                meals[seat] += 1;
+            }
+            Some(philosopher(&mut table, seat, (left.min(right), left.max(right)), &mut phase[seat]))
+        });
+
+        assert!(finished);
+        assert_eq!(meals, [MEALS; SEATS]);
+        assert!(table.mutexes.values().all(|fork| fork.owner().is_none()));
+    }
+
+    #[test_case]
+    fn philosophers_taking_left_fork_first_deadlock() {
+        const SEATS: usize = 5;
+        let mut table = SyncTable::new();
+        for fork in 0..SEATS {
+            table.create_mutex(fork);
+        }
+
+        let mut phase = [0; SEATS];
+        let finished = run_threads(SEATS, 1, |seat| {
+            let forks = (seat, (seat + 1) % SEATS);
+            Some(philosopher(&mut table, seat, forks, &mut phase[seat]))
+        });
+
+        // Everyone holds their left fork and waits for their right
+        assert!(!finished);
+        for seat in 0..SEATS {
+            assert_eq!(table.mutexes[&seat].owner(), Some(tid(seat)));
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::executor::{self, JobContext, SpawnedThread};
+use crate::futex::{FutexError, FUTEXES, FUTEX_WAIT, FUTEX_WAKE};
+use crate::kernel_stack::KernelStack;
+use crate::programs;
+use crate::sync::{SyncEffect, SyncError, WaitChannel, SYNC};
+use crate::timer;
+use crate::task::{ResourceRequirements, Tid};
+use crate::println;
+
+pub const SYSCALL_WRITE: usize = 64;
+pub const SYSCALL_EXIT: usize = 93;
+pub const SYSCALL_FUTEX: usize = 98;
+pub const SYSCALL_BRK: usize = 214;
+pub const SYSCALL_THREAD_CREATE: usize = 1000;
+pub const SYSCALL_GETTID: usize = 1001;
+pub const SYSCALL_WAITTID: usize = 1002;
+pub const SYSCALL_MUTEX_CREATE: usize = 1010;
+pub const SYSCALL_MUTEX_LOCK: usize = 1011;
+pub const SYSCALL_MUTEX_UNLOCK: usize = 1012;
+pub const SYSCALL_SEMAPHORE_CREATE: usize = 1020;
+pub const SYSCALL_SEMAPHORE_UP: usize = 1021;
+pub const SYSCALL_SEMAPHORE_DOWN: usize = 1022;
+pub const SYSCALL_CONDVAR_CREATE: usize = 1030;
+pub const SYSCALL_CONDVAR_SIGNAL: usize = 1031;
+pub const SYSCALL_CONDVAR_WAIT: usize = 1032;
+pub const SYSCALL_RESOURCE_REQUEST: usize = 1040;
+pub const SYSCALL_RESOURCE_RELEASE: usize = 1041;
+
+/// Runs syscall `syscall_id` for the calling thread.
+///
+/// # Safety
+///
+/// Programs share the kernel address space, so buffers are passed as a
+/// pointer and a length and read in place: for `SYSCALL_WRITE` `args[1]`
+/// and `args[2]`, and for `SYSCALL_RESOURCE_REQUEST` and
+/// `SYSCALL_RESOURCE_RELEASE` `args[0]` and `args[1]`, must describe bytes
+/// valid for reads for the duration of the call. For a `FUTEX_WAIT`,
+/// `args[0]` must be null, misaligned, or the address of a 32-bit word
+/// valid for reads for the duration of the call.
+pub unsafe fn syscall(ctx: &mut JobContext, syscall_id: usize, args: [usize; 4]) -> isize {
+    match syscall_id {
+        SYSCALL_WRITE => sys_write(ctx, args[0], args[1] as *const u8, args[2]),
+        SYSCALL_EXIT => sys_exit(ctx, args[0] as i32),
+        SYSCALL_BRK => sys_brk(ctx, args[0]),
+        SYSCALL_FUTEX => sys_futex(ctx, args[0], args[1], args[2] as u32, args[3]),
+        SYSCALL_THREAD_CREATE => sys_thread_create(ctx, args[0], args[1]),
+        SYSCALL_GETTID => ctx.tid.0 as isize,
+        SYSCALL_WAITTID => sys_waittid(ctx, args[0]),
+        SYSCALL_MUTEX_CREATE => {
+            SYNC.lock().create_mutex(args[0]);
+            args[0] as isize
+        }
+        SYSCALL_MUTEX_LOCK => {
+            let result = SYNC.lock().mutex_lock(args[0], ctx.tid);
+            apply(ctx, result)
+        }
+        SYSCALL_MUTEX_UNLOCK => {
+            let result = SYNC.lock().mutex_unlock(args[0], ctx.tid);
+            apply(ctx, result)
+        }
+        SYSCALL_SEMAPHORE_CREATE => {
+            SYNC.lock().create_semaphore(args[0], args[1]);
+            args[0] as isize
+        }
+        SYSCALL_SEMAPHORE_UP => {
+            let result = SYNC.lock().semaphore_up(args[0]);
+            apply(ctx, result)
+        }
+        SYSCALL_SEMAPHORE_DOWN => {
+            let result = SYNC.lock().semaphore_down(args[0], ctx.tid);
+            apply(ctx, result)
+        }
+        SYSCALL_CONDVAR_CREATE => {
+            SYNC.lock().create_condvar(args[0]);
+            args[0] as isize
+        }
+        SYSCALL_CONDVAR_SIGNAL => {
+            let result = SYNC.lock().condvar_signal(args[0]);
+            apply(ctx, result)
+        }
+        SYSCALL_CONDVAR_WAIT => {
+            let result = SYNC.lock().condvar_wait(args[0], args[1], ctx.tid);
+            apply(ctx, result)
+        }
+        SYSCALL_RESOURCE_REQUEST => sys_resource_request(ctx, args[0] as *const u8, args[1], args[2] as u64),
+        SYSCALL_RESOURCE_RELEASE => sys_resource_release(ctx, args[0] as *const u8, args[1], args[2] as u64),
+        _ => {
+            println!("[SYSCALL] Job {}: unsupported syscall {}", ctx.id, syscall_id);
+            -1
+        }
+    }
+}
+
+/// `buf` must be valid for reads of `len` bytes
+unsafe fn sys_write(ctx: &mut JobContext, fd: usize, buf: *const u8, len: usize) -> isize {
+    let bytes = core::slice::from_raw_parts(buf, len);
+    if ctx.write(fd, bytes) {
+        len as isize
+    } else {
+        -1
+    }
+}
+
+/// Ends the calling thread. The job ends once all its threads have, with
+/// the main thread's exit code.
+fn sys_exit(ctx: &mut JobContext, exit_code: i32) -> isize {
+    ctx.exit(exit_code);
+    0
+}
+
+/// Resizes the program's data segment to `size` bytes, returning the new
+/// size, or -1 if that would exceed the memory the job requested
+fn sys_brk(ctx: &mut JobContext, size: usize) -> isize {
+    if ctx.set_memory(size) {
+        size as isize
+    } else {
+        -1
+    }
+}
+
+/// `futex(addr, FUTEX_WAIT, val, timeout)` parks the thread while the
+/// 32-bit word at `addr` holds `val`, for at most `timeout` ticks (0 waits
+/// forever); like other blocking calls it returns 0, and the thread finds
+/// `timed_out` set if no wake came. Returns -2 at once if the word holds
+/// another value. `futex(addr, FUTEX_WAKE, n)` wakes up to `n` waiters,
+/// returning how many it woke. `addr` must be valid as for
+/// `FutexTable::wait`.
+unsafe fn sys_futex(ctx: &mut JobContext, addr: usize, op: usize, val: u32, timeout: usize) -> isize {
+    let result = match op {
+        FUTEX_WAIT => {
+            let deadline = (timeout > 0).then(|| timer::ticks().saturating_add(timeout as u64));
+            FUTEXES.lock().wait(addr, val, ctx.tid, deadline)
+        }
+        FUTEX_WAKE => FUTEXES.lock().wake(addr, val as usize),
+        _ => {
+            println!("[SYSCALL] Job {}: unknown futex operation {}", ctx.id, op);
+            return -1;
+        }
+    };
+    match result {
+        Ok(effect) => {
+            let woken = effect.wake.len();
+            apply(ctx, Ok(effect));
+            woken as isize
+        }
+        Err(FutexError::ValueChanged) => -2,
+        Err(FutexError::BadAddress) => -1,
+    }
+}
+
+/// Starts a thread of the calling program at thread entry point `entry`
+/// (see `programs::thread_entry`), returning its TID, or -1 if there is no
+/// such entry point or no kernel stack is free. It runs once the calling
+/// step returns.
+fn sys_thread_create(ctx: &mut JobContext, entry: usize, arg: usize) -> isize {
+    let Some(entry) = programs::thread_entry(entry) else {
+        println!("[SYSCALL] Job {}: no thread entry point {}", ctx.id, entry);
+        return -1;
+    };
+    let Some(stack) = KernelStack::new(entry) else {
+        println!("[SYSCALL] Job {}: no kernel stack free for a new thread", ctx.id);
+        return -1;
+    };
+    let tid = executor::next_tid();
+    ctx.shared.lock().threads.insert(tid, None);
+    ctx.spawned.push(SpawnedThread { tid, stack, arg });
+    tid.0 as isize
+}
+
+/// Returns the exit code of thread `tid` of the calling program and forgets
+/// it, or -1 if there is no such thread (or it is the caller). If it is
+/// still running, returns -2 and the caller waits until it exits; calling
+/// again then returns its exit code.
+fn sys_waittid(ctx: &mut JobContext, tid: usize) -> isize {
+    let tid = Tid(tid as u64);
+    if tid == ctx.tid {
+        return -1;
+    }
+    let mut shared = ctx.shared.lock();
+    match shared.threads.get(&tid) {
+        None => -1,
+        Some(None) => {
+            // Registered under the lock the exiting thread takes to record
+            // its exit code, so the wake-up can't be missed
+            shared.joiners.entry(tid).or_default().push(ctx.tid);
+            drop(shared);
+            ctx.block_on(WaitChannel::Thread(tid));
+            -2
+        }
+        Some(&Some(exit_code)) => {
+            shared.threads.remove(&tid);
+            exit_code as isize
+        }
+    }
+}
+
+/// `amount` of the resource named by the string at `name`: `cpu`, `memory`
+/// (in KB) or a generic resource. `name` must be valid for reads of `len`
+/// bytes.
+unsafe fn resource_amount(name: *const u8, len: usize, amount: u64) -> Option<ResourceRequirements> {
+    if amount == 0 {
+        return None;
+    }
+    let name = core::slice::from_raw_parts(name, len);
+    Some(match core::str::from_utf8(name).ok()? {
+        "cpu" => ResourceRequirements::new(u32::try_from(amount).ok()?, 0),
+        "memory" => ResourceRequirements::new(0, amount),
+        generic => ResourceRequirements::new(0, 0).with(generic, amount),
+    })
+}
+
+/// Asks for more of a resource for the calling job. Like other blocking
+/// calls it returns 0, the thread waiting until the resource manager can
+/// grant the request; -1 if it never could.
+unsafe fn sys_resource_request(ctx: &mut JobContext, name: *const u8, len: usize, amount: u64) -> isize {
+    let Some(request) = resource_amount(name, len, amount) else {
+        return -1;
+    };
+    let result = ctx.resources.lock().request_resources(ctx.id, ctx.tid, &request);
+    match result {
+        Ok(true) => 0,
+        Ok(false) => {
+            ctx.block_on(WaitChannel::Resources);
+            0
+        }
+        Err(err) => {
+            println!("[SYSCALL] Job {}: request for {} refused: {:?}", ctx.id, request, err);
+            -1
+        }
+    }
+}
+
+/// Returns resources the calling job was granted by `sys_resource_request`,
+/// waking threads whose requests can now be granted
+unsafe fn sys_resource_release(ctx: &mut JobContext, name: *const u8, len: usize, amount: u64) -> isize {
+    let Some(amount) = resource_amount(name, len, amount) else {
+        return -1;
+    };
+    let mut resources = ctx.resources.lock();
+    if let Err(err) = resources.release_part(ctx.id, &amount) {
+        drop(resources);
+        println!("[SYSCALL] Job {}: release of {} refused: {:?}", ctx.id, amount, err);
+        return -1;
+    }
+    let granted = resources.grant_pending();
+    drop(resources);
+    ctx.wake.extend(granted);
+    0
+}
+
+/// Passes a sync operation's effects to the batch system through the
+/// thread's context: threads it woke, and whether the caller must park. A
+/// blocking call returns 0 like any other, but only takes effect once the
+/// thread is woken.
+fn apply(ctx: &mut JobContext, result: Result<SyncEffect, SyncError>) -> isize {
+    match result {
+        Ok(effect) => {
+            ctx.wake.extend(effect.wake);
+            if let Some(channel) = effect.blocked_on {
+                ctx.block_on(channel);
+            }
+            0
+        }
+        Err(err) => {
+            println!("[SYSCALL] Job {}: sync operation failed: {:?}", ctx.id, err);
+            -1
+        }
+    }
+}
@@ -1,25 +1,325 @@
+use alloc::collections::BTreeMap;
 use alloc::string::String;
 use alloc::vec::Vec;
+use bitflags::bitflags;
+use core::fmt;
+
+/// Identifier handed out by the batch system when a task is submitted
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct JobId(pub u64);
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// Identifier of one thread of a running job, unique across all jobs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Tid(pub u64);
+
+impl fmt::Display for Tid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct Task {
     pub executable: String,
     pub arguments: Vec<String>,
+    /// Account charged for the task's usage and subject to its quotas
+    pub account: String,
     pub priority: u32,
     pub resource_requirements: ResourceRequirements,
+    /// Most the task declares it will hold at once, counting resources it
+    /// requests while running. Enforced under the banker's algorithm.
+    pub max_resources: Option<ResourceRequirements>,
+    /// Declared runtime in timer ticks, used to plan backfilling (0 = unknown)
+    pub estimated_runtime: u64,
+    /// Jobs that must reach a terminal state before this task may be queued
+    pub dependencies: Vec<Dependency>,
+    pub limits: TaskLimits,
+    pub retry: RetryPolicy,
+    /// Advance reservation the task runs in, if any
+    pub reservation: Option<String>,
+    /// Harts the task may run on
+    pub affinity: CpuSet,
     pub status: TaskStatus,
 }
 
+impl Task {
+    /// The task's maximum claim: its declared maximum, but never less than
+    /// what it needs to start
+    pub fn max_claim(&self) -> ResourceRequirements {
+        match &self.max_resources {
+            Some(max) => self.resource_requirements.covering(max),
+            None => self.resource_requirements.clone(),
+        }
+    }
+}
+
+/// A set of hart IDs, one bit per hart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuSet(pub u64);
+
+impl CpuSet {
+    pub const fn all() -> Self {
+        CpuSet(u64::MAX)
+    }
+
+    pub const fn single(hart_id: usize) -> Self {
+        CpuSet(1 << hart_id)
+    }
+
+    pub fn with(self, hart_id: usize) -> Self {
+        CpuSet(self.0 | 1 << hart_id)
+    }
+
+    pub fn contains(&self, hart_id: usize) -> bool {
+        hart_id < 64 && self.0 & (1 << hart_id) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl Default for CpuSet {
+    fn default() -> Self {
+        CpuSet::all()
+    }
+}
+
+bitflags! {
+    /// Kinds of failure a retry policy re-queues a task for
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct RetryOn: u8 {
+        /// The task ran to the end and reported failure
+        const FAILED = 1 << 0;
+        /// The task was killed for exceeding a time limit
+        const TIMED_OUT = 1 << 1;
+        /// The task was killed by a fault it raised
+        const FAULT = 1 << 2;
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Total attempts allowed, including the first run
+    pub max_attempts: u32,
+    /// Ticks to wait before re-queueing a failed attempt
+    pub backoff: u64,
+    pub retry_on: RetryOn,
+}
+
+impl RetryPolicy {
+    pub const fn never() -> Self {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff: 0,
+            retry_on: RetryOn::empty(),
+        }
+    }
+
+    pub fn should_retry(&self, failure: RetryOn, attempts_made: u32) -> bool {
+        self.retry_on.intersects(failure) && attempts_made < self.max_attempts
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::never()
+    }
+}
+
+/// Time limits in timer ticks; a task exceeding one is killed as `TimedOut`
+#[derive(Debug, Clone, Default)]
+pub struct TaskLimits {
+    /// Ticks since the task started, whether or not it was on a CPU
+    pub wall_time: Option<u64>,
+    /// Ticks the task actually spent running, summed over the harts its threads ran on
+    pub cpu_time: Option<u64>,
+}
+
+impl TaskLimits {
+    pub const fn unlimited() -> Self {
+        TaskLimits {
+            wall_time: None,
+            cpu_time: None,
+        }
+    }
+
+    /// Fills in any limit this task leaves unset from `defaults`
+    pub fn or(&self, defaults: &TaskLimits) -> TaskLimits {
+        TaskLimits {
+            wall_time: self.wall_time.or(defaults.wall_time),
+            cpu_time: self.cpu_time.or(defaults.cpu_time),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DependencyKind {
+    /// Run only after the job completed successfully
+    AfterOk,
+    /// Run after the job finished, whatever the outcome
+    AfterAny,
+    /// Run only after the job failed
+    AfterFail,
+}
+
+#[derive(Debug, Clone)]
+pub struct Dependency {
+    pub job: JobId,
+    pub kind: DependencyKind,
+}
+
+impl Dependency {
+    pub fn after_ok(job: JobId) -> Self {
+        Dependency { job, kind: DependencyKind::AfterOk }
+    }
+
+    pub fn after_any(job: JobId) -> Self {
+        Dependency { job, kind: DependencyKind::AfterAny }
+    }
+
+    pub fn after_fail(job: JobId) -> Self {
+        Dependency { job, kind: DependencyKind::AfterFail }
+    }
+
+    /// Whether the dependency is met (`Some(true)`), can never be met
+    /// (`Some(false)`), or still waiting on the job (`None`)
+    pub fn evaluate(&self, status: Option<&TaskStatus>) -> Option<bool> {
+        let status = status?;
+        if !status.is_terminal() {
+            return None;
+        }
+        Some(match self.kind {
+            DependencyKind::AfterOk => matches!(status, TaskStatus::Completed),
+            DependencyKind::AfterAny => true,
+            DependencyKind::AfterFail => status.is_failure(),
+        })
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct ResourceRequirements {
     pub cpu: u32,
-    pub memory: u32,
+    /// Memory in KB
+    pub memory: u64,
+    /// Named countable resources such as licences, scratch disk or devices
+    pub generic: BTreeMap<String, u64>,
+}
+
+impl ResourceRequirements {
+    pub fn new(cpu: u32, memory: u64) -> Self {
+        ResourceRequirements {
+            cpu,
+            memory,
+            generic: BTreeMap::new(),
+        }
+    }
+
+    /// Adds `amount` of the named generic resource
+    pub fn with(mut self, name: &str, amount: u64) -> Self {
+        self.set_generic(name, amount);
+        self
+    }
+
+    pub fn set_generic(&mut self, name: &str, amount: u64) {
+        if amount == 0 {
+            self.generic.remove(name);
+        } else {
+            self.generic.insert(String::from(name), amount);
+        }
+    }
+
+    pub fn get_generic(&self, name: &str) -> u64 {
+        self.generic.get(name).copied().unwrap_or(0)
+    }
+
+    pub fn fits_within(&self, available: &ResourceRequirements) -> bool {
+        self.cpu <= available.cpu
+            && self.memory <= available.memory
+            && self
+                .generic
+                .iter()
+                .all(|(name, &amount)| amount <= available.get_generic(name))
+    }
+
+    pub fn add(&mut self, other: &ResourceRequirements) {
+        self.cpu += other.cpu;
+        self.memory += other.memory;
+        for (name, &amount) in &other.generic {
+            *self.generic.entry(name.clone()).or_insert(0) += amount;
+        }
+    }
+
+    /// The smallest requirements that both `self` and `other` fit within
+    pub fn covering(&self, other: &ResourceRequirements) -> ResourceRequirements {
+        let mut covering = ResourceRequirements::new(self.cpu.max(other.cpu), self.memory.max(other.memory));
+        for name in self.generic.keys().chain(other.generic.keys()) {
+            covering.set_generic(name, self.get_generic(name).max(other.get_generic(name)));
+        }
+        covering
+    }
+
+    /// What `self` needs beyond `other`, with nothing going below zero
+    pub fn saturating_sub(&self, other: &ResourceRequirements) -> ResourceRequirements {
+        let mut remaining = ResourceRequirements::new(
+            self.cpu.saturating_sub(other.cpu),
+            self.memory.saturating_sub(other.memory),
+        );
+        for (name, &amount) in &self.generic {
+            remaining.set_generic(name, amount.saturating_sub(other.get_generic(name)));
+        }
+        remaining
+    }
+
+    pub fn subtract(&mut self, other: &ResourceRequirements) {
+        self.cpu -= other.cpu;
+        self.memory -= other.memory;
+        for (name, &amount) in &other.generic {
+            if let Some(available) = self.generic.get_mut(name) {
+                *available -= amount;
+            }
+        }
+    }
+}
+
+impl fmt::Display for ResourceRequirements {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "CPU={}, Memory={}KB", self.cpu, self.memory)?;
+        for (name, amount) in &self.generic {
+            write!(f, ", {}={}", name, amount)?;
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
 pub enum TaskStatus {
-    Queued,
+    /// Waiting in the scheduler queue
+    Pending,
+    /// Waiting on dependencies before it may be queued
+    Blocked,
     Running,
     Completed,
     Failed,
+    Cancelled,
+    TimedOut,
+}
+
+impl TaskStatus {
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            TaskStatus::Completed | TaskStatus::Failed | TaskStatus::Cancelled | TaskStatus::TimedOut
+        )
+    }
+
+    pub fn is_failure(&self) -> bool {
+        matches!(self, TaskStatus::Failed | TaskStatus::TimedOut)
+    }
 }
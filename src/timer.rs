@@ -0,0 +1,54 @@
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicU64, Ordering};
+use riscv::register::mhartid;
+use crate::println;
+
+// QEMU virt machine CLINT addresses
+const CLINT_BASE: usize = 0x0200_0000;
+const CLINT_MTIMECMP: usize = CLINT_BASE + 0x4000;
+const CLINT_MTIME: usize = CLINT_BASE + 0xBFF8;
+
+// QEMU virt machine timebase runs at 10 MHz
+pub const TIMEBASE_FREQUENCY: u64 = 10_000_000;
+pub const TICKS_PER_SECOND: u64 = 100;
+pub const TICK_INTERVAL: u64 = TIMEBASE_FREQUENCY / TICKS_PER_SECOND;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer ticks since the timer was started
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+fn read_mtime() -> u64 {
+    unsafe { read_volatile(CLINT_MTIME as *const u64) }
+}
+
+fn set_next_timer() {
+    let mtimecmp = (CLINT_MTIMECMP + 8 * mhartid::read()) as *mut u64;
+    unsafe {
+        write_volatile(mtimecmp, read_mtime() + TICK_INTERVAL);
+    }
+}
+
+/// Called from the trap handler on every machine timer interrupt. Every hart
+/// gets timer interrupts to wake its idle loop, but only the boot hart counts
+/// ticks.
+pub fn handle_timer_interrupt() {
+    crate::percpu::this().ticks.fetch_add(1, Ordering::Relaxed);
+    if crate::smp::is_boot_hart() {
+        let now = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+        crate::watchdog::on_tick(now);
+    }
+    set_next_timer();
+}
+
+pub fn init() {
+    init_hart();
+    println!("Timer initialized ({} ticks per second)", TICKS_PER_SECOND);
+}
+
+/// Arms the calling hart's timer
+pub fn init_hart() {
+    set_next_timer();
+}
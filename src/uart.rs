@@ -1,6 +1,6 @@
 use core::fmt;
 use lazy_static::lazy_static;
-use spin::Mutex;
+use crate::irq_lock::IrqSafeMutex;
 use core::ptr::{read_volatile, write_volatile};
 use crate::println;
 
@@ -20,7 +20,7 @@ struct UartRegisters {
 }
 
 lazy_static! {
-    pub static ref UART: Mutex<Uart> = Mutex::new(Uart::new());
+    pub static ref UART: IrqSafeMutex<Uart> = IrqSafeMutex::new(Uart::new());
 }
 
 pub struct Uart {
@@ -0,0 +1,159 @@
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use crate::percpu;
+use crate::task::{JobId, TaskLimits};
+
+/// Most jobs that can be watched, and so run, at the same time
+pub const MAX_WATCHED_JOBS: usize = 16;
+
+const NO_JOB: u64 = 0;
+/// Held by `start` while it fills in a slot it has claimed
+const CLAIMED: u64 = u64::MAX;
+const NO_LIMIT: u64 = u64::MAX;
+
+const OVERRUN_NONE: u8 = 0;
+const OVERRUN_WALL_TIME: u8 = 1;
+const OVERRUN_CPU_TIME: u8 = 2;
+
+// State of each running job. Written by the batch system when it starts and
+// stops a job and checked by the timer interrupt on every tick, so everything
+// is atomic rather than behind a lock.
+struct Slot {
+    job: AtomicU64,
+    started_at: AtomicU64,
+    cpu_ticks: AtomicU64,
+    wall_limit: AtomicU64,
+    cpu_limit: AtomicU64,
+    overrun: AtomicU8,
+}
+
+impl Slot {
+    const fn new() -> Self {
+        Slot {
+            job: AtomicU64::new(NO_JOB),
+            started_at: AtomicU64::new(0),
+            cpu_ticks: AtomicU64::new(0),
+            wall_limit: AtomicU64::new(NO_LIMIT),
+            cpu_limit: AtomicU64::new(NO_LIMIT),
+            overrun: AtomicU8::new(OVERRUN_NONE),
+        }
+    }
+
+    fn flag(&self, overrun: u8) {
+        let _ = self.overrun.compare_exchange(OVERRUN_NONE, overrun, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+static SLOTS: [Slot; MAX_WATCHED_JOBS] = [const { Slot::new() }; MAX_WATCHED_JOBS];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    WallTime,
+    CpuTime,
+}
+
+/// A limit a job ran past, kept for its job record
+#[derive(Debug, Clone)]
+pub struct Overrun {
+    pub kind: LimitKind,
+    pub limit: u64,
+    pub used: u64,
+}
+
+/// Time a job used while it was being watched
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Usage {
+    pub wall_ticks: u64,
+    pub cpu_ticks: u64,
+}
+
+fn slot(job: JobId) -> Option<&'static Slot> {
+    SLOTS.iter().find(|slot| slot.job.load(Ordering::SeqCst) == job.0)
+}
+
+/// Starts watching a job, returning false if every slot is taken
+pub fn start(job: JobId, limits: &TaskLimits, now: u64) -> bool {
+    // Claim the slot before filling it in, so two harts can't take the same
+    // one and the tick doesn't check a job against the last one's limits
+    let claimed = SLOTS.iter().find(|slot| {
+        slot.job
+            .compare_exchange(NO_JOB, CLAIMED, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+    });
+    let Some(slot) = claimed else {
+        return false;
+    };
+    slot.started_at.store(now, Ordering::SeqCst);
+    slot.cpu_ticks.store(0, Ordering::SeqCst);
+    slot.wall_limit.store(limits.wall_time.unwrap_or(NO_LIMIT), Ordering::SeqCst);
+    slot.cpu_limit.store(limits.cpu_time.unwrap_or(NO_LIMIT), Ordering::SeqCst);
+    slot.overrun.store(OVERRUN_NONE, Ordering::SeqCst);
+    slot.job.store(job.0, Ordering::SeqCst);
+    true
+}
+
+/// Stops watching a job and returns how much time it used
+pub fn stop(job: JobId, now: u64) -> Usage {
+    let Some(slot) = slot(job) else {
+        return Usage::default();
+    };
+    let usage = Usage {
+        wall_ticks: now.saturating_sub(slot.started_at.load(Ordering::SeqCst)),
+        cpu_ticks: slot.cpu_ticks.load(Ordering::SeqCst),
+    };
+    for cpu in percpu::all() {
+        cpu.clear_current_job(job);
+    }
+    // Only free the slot once its usage is read, as `start` may reuse it at once
+    slot.job.store(NO_JOB, Ordering::SeqCst);
+    usage
+}
+
+/// Marks the job the calling hart is now giving a time slice, or None between slices
+pub fn set_on_cpu(job: Option<JobId>) {
+    percpu::this().set_current_job(job);
+}
+
+/// Number of harts running one of the job's threads
+fn harts_running(job: u64) -> u64 {
+    percpu::all().filter(|cpu| cpu.current_job() == Some(JobId(job))).count() as u64
+}
+
+/// Called from the boot hart's timer interrupt: charges the tick to every
+/// job once for each hart it is on, and flags any job that has run past one
+/// of its limits
+pub fn on_tick(now: u64) {
+    for slot in &SLOTS {
+        let job = slot.job.load(Ordering::SeqCst);
+        if job == NO_JOB || job == CLAIMED {
+            continue;
+        }
+
+        let harts = harts_running(job);
+        let cpu_ticks = slot.cpu_ticks.fetch_add(harts, Ordering::SeqCst) + harts;
+        let wall_ticks = now.saturating_sub(slot.started_at.load(Ordering::SeqCst));
+
+        if wall_ticks > slot.wall_limit.load(Ordering::SeqCst) {
+            slot.flag(OVERRUN_WALL_TIME);
+        } else if cpu_ticks > slot.cpu_limit.load(Ordering::SeqCst) {
+            slot.flag(OVERRUN_CPU_TIME);
+        }
+    }
+}
+
+/// The limit the job has exceeded, if any
+pub fn overrun(job: JobId, now: u64) -> Option<Overrun> {
+    let slot = slot(job)?;
+    match slot.overrun.load(Ordering::SeqCst) {
+        OVERRUN_WALL_TIME => Some(Overrun {
+            kind: LimitKind::WallTime,
+            limit: slot.wall_limit.load(Ordering::SeqCst),
+            used: now.saturating_sub(slot.started_at.load(Ordering::SeqCst)),
+        }),
+        OVERRUN_CPU_TIME => Some(Overrun {
+            kind: LimitKind::CpuTime,
+            limit: slot.cpu_limit.load(Ordering::SeqCst),
+            used: slot.cpu_ticks.load(Ordering::SeqCst),
+        }),
+        _ => None,
+    }
+}
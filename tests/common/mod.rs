@@ -1,19 +1,10 @@
 pub mod common {
 	use super::*;
 
-	pub fn setup_test_scheduler() -> (Scheduler, Arc<Mutex<ResourceManager>>) {
-			let resource_manager = Arc::new(Mutex::new(ResourceManager::new(ResourceRequirements {
-					cpu: 8,
-					memory: 16_000,
-			})));
+	pub fn setup_test_scheduler() -> (Scheduler, Arc<SpinMutex<ResourceManager>>) {
+			let resource_manager = Arc::new(SpinMutex::new(ResourceManager::new(ResourceRequirements::new(8, 16_000))));
 
-			let scheduler = Scheduler::new(
-					ResourceRequirements {
-							cpu: 8,
-							memory: 16_000,
-					},
-					Arc::clone(&resource_manager),
-			);
+			let scheduler = Scheduler::new(Arc::clone(&resource_manager));
 
 			(scheduler, resource_manager)
 	}
@@ -22,11 +13,17 @@ pub mod common {
 			Task {
 					executable: format!("task{}", id),
 					arguments: vec![format!("arg{}_1", id), format!("arg{}_2", id)],
+					account: String::from("default"),
 					priority,
-					resource_requirements: ResourceRequirements {
-							cpu,
-							memory,
-					},
+					resource_requirements: ResourceRequirements::new(cpu, memory),
+					max_resources: None,
+					estimated_runtime: 0,
+					dependencies: Vec::new(),
+					limits: TaskLimits::default(),
+					retry: RetryPolicy::default(),
+					reservation: None,
+					affinity: CpuSet::all(),
+					status: TaskStatus::Pending,
 			}
 	}
 }